@@ -0,0 +1,5 @@
+fn main() {
+    // link flags Node.js addons need on some platforms
+    #[cfg(feature = "napi")]
+    napi_build::setup();
+}
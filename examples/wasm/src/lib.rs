@@ -0,0 +1,17 @@
+use js_sys::Uint8Array;
+use wasm_bindgen::prelude::*;
+
+/// Builds a collage of the encoded reference image `ref_img_bytes` out of the encoded `tile_images`
+/// and returns it encoded as PNG. Errors are thrown as JS exceptions.
+#[wasm_bindgen]
+pub fn wasm_collage(
+    ref_img_bytes: &[u8],
+    tile_images: Vec<Uint8Array>,
+    cols: u32,
+    rows: u32,
+    alpha: f32,
+) -> Result<Vec<u8>, JsError> {
+    let tiles: Vec<Vec<u8>> = tile_images.iter().map(Uint8Array::to_vec).collect();
+    recreate::collage_png(ref_img_bytes, &tiles, cols, rows, alpha)
+        .map_err(|e| JsError::new(&format!("{:#}", e)))
+}
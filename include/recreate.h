@@ -0,0 +1,48 @@
+/*
+ * C API of recreate, built with `cargo build --release --features ffi`.
+ * Link against target/release/librecreate.so (librecreate.dylib on macOS, recreate.dll on Windows).
+ *
+ * Functions returning int return 0 on success and -1 on failure, in which case
+ * recreate_last_error() describes what went wrong.
+ * A context may be used from several threads at once: collages are built concurrently, while
+ * recreate_load_dir waits for the collages in progress.
+ */
+
+#ifndef RECREATE_H
+#define RECREATE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Recreate Recreate;
+
+/* Creates an empty context, to be released with recreate_free(). */
+Recreate *recreate_create(void);
+
+/* Adds every image in the directory at path (UTF-8) to the context's pool. */
+int recreate_load_dir(Recreate *ctx, const char *path);
+
+/*
+ * Builds a collage of the image at ref_path (UTF-8) out of the loaded pool with a cols x rows grid,
+ * blending tiles towards their cell's color by alpha (0.0 to 1.0).
+ * The collage is saved as output.png in the reference image's directory.
+ */
+int recreate_collage(Recreate *ctx, const char *ref_path, uint32_t cols, uint32_t rows, float alpha);
+
+/* Releases a context. Passing NULL does nothing. */
+void recreate_free(Recreate *ctx);
+
+/*
+ * Message of the last error raised on the calling thread, or NULL if there has been none.
+ * The string is owned by the library and is only valid until the next failing call on this thread.
+ */
+const char *recreate_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
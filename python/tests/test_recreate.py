@@ -0,0 +1,49 @@
+import zlib
+import struct
+
+import pytest
+
+import recreate
+
+
+def write_ppm(path, width, height, color):
+    with open(path, "wb") as f:
+        f.write(b"P6\n%d %d\n255\n" % (width, height))
+        f.write(bytes(color) * (width * height))
+
+
+@pytest.fixture
+def pool(tmp_path):
+    pool_dir = tmp_path / "pool"
+    pool_dir.mkdir()
+    for i, color in enumerate([(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255)]):
+        write_ppm(pool_dir / f"tile{i}.ppm", 16, 16, color)
+    return pool_dir
+
+
+def png_size(data):
+    assert data[:8] == b"\x89PNG\r\n\x1a\n"
+    # IHDR is always the first chunk
+    width, height = struct.unpack(">II", data[16:24])
+    assert zlib.crc32(data[12:29]) == struct.unpack(">I", data[29:33])[0]
+    return width, height
+
+
+def test_collage_round_trip(tmp_path, pool):
+    ref_dir = tmp_path / "ref"
+    ref_dir.mkdir()
+    write_ppm(ref_dir / "ref.ppm", 40, 40, (200, 100, 50))
+
+    r = recreate.Recreate()
+    r.load_dir(str(pool))
+
+    png = r.collage(str(ref_dir / "ref.ppm"), cols=4, rows=4, alpha=0.5, in_memory=True)
+    assert png_size(png) == (40, 40)
+
+    assert r.collage(str(ref_dir / "ref.ppm"), cols=4, rows=4) is None
+    assert png_size((ref_dir / "output.png").read_bytes()) == (40, 40)
+
+
+def test_load_dir_missing(tmp_path):
+    with pytest.raises(RuntimeError):
+        recreate.Recreate().load_dir(str(tmp_path / "missing"))
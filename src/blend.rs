@@ -0,0 +1,26 @@
+//! Contrast matching of tiles to their reference cell for --contrast-transfer.
+
+use crate::color_transfer::{color_transfer, statistics, to_lab};
+use crate::lab_to_rgba_u8;
+use image::DynamicImage;
+use palette::Lab;
+
+/// Scales the deviation of every Lab channel of `tile` from its own mean to the standard deviation of `reference_cell`.
+/// Unlike --color-transfer the means of the tile are kept, so it keeps its colors and only takes on the contrast of its cell.
+pub fn contrast_match(tile: DynamicImage, reference_cell: &DynamicImage) -> DynamicImage {
+    let mut tile = tile.into_rgba8();
+    let tile_labs: Vec<Lab> = tile.pixels().map(|pixel| to_lab(pixel.0)).collect();
+    let reference_labs: Vec<Lab> = reference_cell
+        .to_rgba8()
+        .pixels()
+        .map(|pixel| to_lab(pixel.0))
+        .collect();
+    let (mean, std) = statistics(&tile_labs);
+    let (_, target_std) = statistics(&reference_labs);
+
+    for (pixel, lab) in tile.pixels_mut().zip(&tile_labs) {
+        let lab = color_transfer(lab, &mean, &std, &mean, &target_std);
+        *pixel = lab_to_rgba_u8(lab, Some(pixel[3]));
+    }
+    DynamicImage::ImageRgba8(tile)
+}
@@ -0,0 +1,74 @@
+//! Reinhard et al.'s color transfer (2001) for --color-transfer: shifts and scales every Lab channel of a tile so its
+//! mean and standard deviation match those of the reference cell.
+
+use crate::lab_to_rgba_u8;
+use image::DynamicImage;
+use palette::{IntoColor, Lab, Srgb};
+
+/// Standard deviations below this are treated as a flat channel, which is only shifted to the target mean.
+const MIN_STD: f32 = 1e-3;
+
+/// Moves `src` from the Lab statistics of its own image to the target statistics: every channel is normalised by the
+/// source mean and standard deviation, scaled by the target standard deviation and offset by the target mean.
+pub fn color_transfer(
+    src: &Lab,
+    source_mean: &[f32; 3],
+    source_std: &[f32; 3],
+    target_mean: &[f32; 3],
+    target_std: &[f32; 3],
+) -> Lab {
+    let channel = |value: f32, idx: usize| {
+        let scale = if source_std[idx] < MIN_STD {
+            0.0
+        } else {
+            target_std[idx] / source_std[idx]
+        };
+        (value - source_mean[idx]) * scale + target_mean[idx]
+    };
+    Lab::new(channel(src.l, 0), channel(src.a, 1), channel(src.b, 2))
+}
+
+/// Transfers the Lab statistics of `reference` onto every pixel of `tile`, keeping the tile's alpha.
+pub fn transfer_tile(tile: DynamicImage, reference: &DynamicImage) -> DynamicImage {
+    let mut tile = tile.into_rgba8();
+    let tile_labs: Vec<Lab> = tile.pixels().map(|pixel| to_lab(pixel.0)).collect();
+    let reference_labs: Vec<Lab> = reference
+        .to_rgba8()
+        .pixels()
+        .map(|pixel| to_lab(pixel.0))
+        .collect();
+    let (source_mean, source_std) = statistics(&tile_labs);
+    let (target_mean, target_std) = statistics(&reference_labs);
+
+    for (pixel, lab) in tile.pixels_mut().zip(&tile_labs) {
+        let lab = color_transfer(lab, &source_mean, &source_std, &target_mean, &target_std);
+        *pixel = lab_to_rgba_u8(lab, Some(pixel[3]));
+    }
+    DynamicImage::ImageRgba8(tile)
+}
+
+pub fn to_lab([r, g, b, _]: [u8; 4]) -> Lab {
+    Srgb::new(r, g, b).into_format().into_color()
+}
+
+/// Per-channel mean and standard deviation of `labs`.
+pub fn statistics(labs: &[Lab]) -> ([f32; 3], [f32; 3]) {
+    let count = labs.len().max(1) as f32;
+    let channels = |lab: &Lab| [lab.l, lab.a, lab.b];
+
+    let mut mean = [0.0; 3];
+    for lab in labs {
+        for (sum, value) in mean.iter_mut().zip(channels(lab)) {
+            *sum += value;
+        }
+    }
+    mean = mean.map(|sum| sum / count);
+
+    let mut variance = [0.0; 3];
+    for lab in labs {
+        for ((sum, value), mean) in variance.iter_mut().zip(channels(lab)).zip(mean) {
+            *sum += (value - mean).powi(2);
+        }
+    }
+    (mean, variance.map(|sum| (sum / count).sqrt()))
+}
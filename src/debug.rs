@@ -0,0 +1,309 @@
+use ab_glyph::{FontRef, PxScale};
+use image::{ImageBuffer, Rgba};
+use imageproc::drawing::{draw_hollow_rect_mut, draw_text_mut, text_size};
+use imageproc::rect::Rect;
+use palette::{FromColor, Hsv, Srgb};
+
+// Small monospace font bundled with the binary for debug overlays.
+// DejaVu Sans Mono, see assets/DejaVuSansMono-LICENSE.txt
+const FONT_BYTES: &[u8] = include_bytes!("../assets/DejaVuSansMono.ttf");
+
+// Largest text size used for labels, cells bigger than this don't get bigger text
+const MAX_TEXT_SCALE: f32 = 14.0;
+
+fn font() -> FontRef<'static> {
+    FontRef::try_from_slice(FONT_BYTES).expect("bundled font should be valid")
+}
+
+/// Label drawn on a debug grid cell.
+pub struct CellLabel {
+    pub source: String,
+    pub dom_color: Rgba<u8>,
+}
+
+/// Returns black or white, whichever contrasts more with `background`.
+pub fn contrast_text_color(background: Rgba<u8>) -> Rgba<u8> {
+    let Rgba([r, g, b, _]) = background;
+    // Rec. 709 luma
+    let luma = 0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32;
+    if luma > 128.0 {
+        Rgba([0, 0, 0, 255])
+    } else {
+        Rgba([255, 255, 255, 255])
+    }
+}
+
+/// Cuts `text` down to the longest prefix that fits in `max_width` pixels at `scale`.
+fn truncate_to_width(text: &str, scale: PxScale, font: &FontRef, max_width: u32) -> String {
+    let mut truncated: String = text.to_owned();
+    while !truncated.is_empty() && text_size(scale, font, &truncated).0 > max_width {
+        truncated.pop();
+    }
+    truncated
+}
+
+/// Draws each line of `lines` from the top-left corner of the cell at (`x`, `y`), truncated to the cell width.
+/// Lines that don't fit in the cell height are skipped.
+fn draw_cell_text(
+    canvas: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    (x, y, width, height): (u32, u32, u32, u32),
+    lines: &[&str],
+    color: Rgba<u8>,
+    font: &FontRef,
+) {
+    let scale = PxScale::from((height as f32 / lines.len() as f32).min(MAX_TEXT_SCALE));
+    let line_height = scale.y.ceil() as u32;
+
+    for (i, line) in lines.iter().enumerate() {
+        let line_y = y + i as u32 * line_height;
+        if line_y + line_height > y + height {
+            break;
+        }
+        let text = truncate_to_width(line, scale, font, width.saturating_sub(2));
+        draw_text_mut(
+            canvas,
+            color,
+            x as i32 + 1,
+            line_y as i32,
+            scale,
+            font,
+            &text,
+        );
+    }
+}
+
+/// Returns a copy of the collage with every cell outlined and labelled with the source image it was filled with
+/// and the dominant color it was blended towards.
+/// `labels` holds one entry per grid cell in row-major order, cells without a label are left untouched.
+pub fn draw_debug_grid(
+    collage: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    cell_width: u32,
+    cell_height: u32,
+    grid_cols: u32,
+    labels: &[Option<CellLabel>],
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let font = font();
+    let mut canvas = collage.clone();
+
+    for (idx, label) in labels.iter().enumerate() {
+        let x = (idx as u32 % grid_cols) * cell_width;
+        let y = (idx as u32 / grid_cols) * cell_height;
+        let Some(label) = label else {
+            continue;
+        };
+
+        let color = contrast_text_color(label.dom_color);
+        draw_hollow_rect_mut(
+            &mut canvas,
+            Rect::at(x as i32, y as i32).of_size(cell_width, cell_height),
+            color,
+        );
+        let hex = crate::rgba_to_hex(label.dom_color);
+        draw_cell_text(
+            &mut canvas,
+            (x, y, cell_width, cell_height),
+            &[&label.source, &hex],
+            color,
+            &font,
+        );
+    }
+
+    canvas
+}
+
+/// Returns a copy of the collage with every cell numbered by its flat (row-major, 0-based) index.
+pub fn draw_cell_indices(
+    collage: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    cell_width: u32,
+    cell_height: u32,
+    grid_cols: u32,
+    grid_rows: u32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let font = font();
+    let mut canvas = collage.clone();
+
+    for idx in 0..grid_cols * grid_rows {
+        let x = (idx % grid_cols) * cell_width;
+        let y = (idx / grid_cols) * cell_height;
+        // contrast against what is actually in the cell, which may be mirrored or zoomed content
+        let color = contrast_text_color(mean_color(collage, x, y, cell_width, cell_height));
+        draw_cell_text(
+            &mut canvas,
+            (x, y, cell_width, cell_height),
+            &[&idx.to_string()],
+            color,
+            &font,
+        );
+    }
+
+    canvas
+}
+
+/// Average color of the `width`x`height` area at (`x`, `y`).
+fn mean_color(
+    image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Rgba<u8> {
+    let mut sum = [0u64; 3];
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            let pixel = image.get_pixel(px, py);
+            for c in 0..3 {
+                sum[c] += pixel[c] as u64;
+            }
+        }
+    }
+
+    let count = (width as u64 * height as u64).max(1);
+    Rgba([
+        (sum[0] / count) as u8,
+        (sum[1] / count) as u8,
+        (sum[2] / count) as u8,
+        255,
+    ])
+}
+
+/// Fully saturated, full brightness color with the same hue as `color`.
+fn hue_color(color: Rgba<u8>) -> Rgba<u8> {
+    let Rgba([r, g, b, _]) = color;
+    let hsv = Hsv::from_color(Srgb::new(r, g, b).into_format::<f32>());
+    let rgb: Srgb<u8> = Srgb::from_color(Hsv::new(hsv.hue, 1.0, 1.0)).into_format();
+    Rgba([rgb.red, rgb.green, rgb.blue, 255])
+}
+
+/// Returns a copy of the reference image with 1px lines along every cell boundary.
+/// Each cell draws its top and left boundary in the hue of its dominant color in `cell_colors` (row-major),
+/// the right and bottom edges of the image are drawn by the last column and row.
+pub fn draw_ref_grid_overlay(
+    ref_img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    cell_width: u32,
+    cell_height: u32,
+    grid_cols: u32,
+    cell_colors: &[Rgba<u8>],
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let mut canvas = ref_img.clone();
+    let (width, height) = canvas.dimensions();
+    let grid_rows = cell_colors.len() as u32 / grid_cols;
+
+    for (idx, &dom_color) in cell_colors.iter().enumerate() {
+        let col = idx as u32 % grid_cols;
+        let row = idx as u32 / grid_cols;
+        let x0 = col * cell_width;
+        let y0 = row * cell_height;
+        let x1 = (x0 + cell_width).min(width) - 1;
+        let y1 = (y0 + cell_height).min(height) - 1;
+        let color = hue_color(dom_color);
+
+        for x in x0..=x1 {
+            canvas.put_pixel(x, y0, color);
+            if row == grid_rows - 1 {
+                canvas.put_pixel(x, y1, color);
+            }
+        }
+        for y in y0..=y1 {
+            canvas.put_pixel(x0, y, color);
+            if col == grid_cols - 1 {
+                canvas.put_pixel(x1, y, color);
+            }
+        }
+    }
+
+    canvas
+}
+
+// Height of the label band above each collage of a strategy comparison
+const COMPARISON_LABEL_HEIGHT: u32 = 24;
+
+/// Places `collages` side by side on a white canvas, each under a band with its entry of `labels`.
+pub fn draw_comparison(
+    collages: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+    labels: &[String],
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let font = font();
+    let width = collages.iter().map(|img| img.width()).sum();
+    let height = collages.iter().map(|img| img.height()).max().unwrap_or(0);
+    let mut canvas = ImageBuffer::from_pixel(
+        width,
+        height + COMPARISON_LABEL_HEIGHT,
+        Rgba([255, 255, 255, 255]),
+    );
+
+    let mut x = 0;
+    for (img, label) in collages.iter().zip(labels) {
+        draw_cell_text(
+            &mut canvas,
+            (x, 4, img.width(), COMPARISON_LABEL_HEIGHT - 4),
+            &[label],
+            Rgba([0, 0, 0, 255]),
+            &font,
+        );
+        image::imageops::replace(&mut canvas, img, x as i64, COMPARISON_LABEL_HEIGHT as i64);
+        x += img.width();
+    }
+
+    canvas
+}
+
+// Lab distance drawn at the red end of the distance heat map, larger distances are clamped to it
+const HEATMAP_MAX_DISTANCE: f32 = 50.0;
+
+// Stops of the heat map color scale from a perfect match (green) over yellow to a poor one (red)
+const HEATMAP_STOPS: [[u8; 3]; 5] = [
+    [26, 152, 80],
+    [145, 207, 96],
+    [254, 224, 139],
+    [252, 141, 89],
+    [215, 48, 39],
+];
+
+// 256-entry lookup table interpolating the heat map stops
+const HEATMAP_LUT: [[u8; 3]; 256] = heatmap_lut();
+
+const fn heatmap_lut() -> [[u8; 3]; 256] {
+    let mut lut = [[0; 3]; 256];
+    let segments = HEATMAP_STOPS.len() - 1;
+    let mut idx = 0;
+    while idx < 256 {
+        let position = idx * segments;
+        let stop = position / 255;
+        let (from, to, t) = if stop == segments {
+            (HEATMAP_STOPS[segments], HEATMAP_STOPS[segments], 0)
+        } else {
+            (HEATMAP_STOPS[stop], HEATMAP_STOPS[stop + 1], position % 255)
+        };
+        let mut channel = 0;
+        while channel < 3 {
+            lut[idx][channel] =
+                ((from[channel] as usize * (255 - t) + to[channel] as usize * t) / 255) as u8;
+            channel += 1;
+        }
+        idx += 1;
+    }
+    lut
+}
+
+/// Heat map of how well every tile matches its cell, one `cell_width`x`cell_height` block per entry of `distances`
+/// (row-major) colored by the Lab distance between the dominant colors of the cell and its tile:
+/// green for a close match up to red for a distance of `HEATMAP_MAX_DISTANCE` or more. Cells without a tile stay transparent.
+pub fn draw_distance_heatmap(
+    distances: &[Option<f32>],
+    cell_width: u32,
+    cell_height: u32,
+    grid_cols: u32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let grid_rows = distances.len() as u32 / grid_cols;
+    ImageBuffer::from_fn(grid_cols * cell_width, grid_rows * cell_height, |x, y| {
+        let idx = (y / cell_height * grid_cols + x / cell_width) as usize;
+        match distances[idx] {
+            Some(distance) => {
+                let level = (distance / HEATMAP_MAX_DISTANCE).clamp(0.0, 1.0) * 255.0;
+                let [r, g, b] = HEATMAP_LUT[level.round() as usize];
+                Rgba([r, g, b, 255])
+            }
+            None => Rgba([0, 0, 0, 0]),
+        }
+    })
+}
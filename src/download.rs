@@ -0,0 +1,71 @@
+//! Downloads over HTTP(S), for --manifest images and --ref URLs. Needs the `http` feature.
+
+use anyhow::Result;
+#[cfg(feature = "http")]
+use std::fs;
+use std::path::Path;
+
+/// Whether `location` is an HTTP(S) URL rather than a local path.
+pub fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Last path segment of `url`, made safe to use as a file name. It keeps the extension the image format
+/// is usually told from.
+pub fn file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    path.rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub struct HttpClient {
+    #[cfg(feature = "http")]
+    agent: ureq::Agent,
+}
+
+impl HttpClient {
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    pub fn new(user_agent: Option<&str>) -> Self {
+        #[cfg(feature = "http")]
+        let agent = {
+            // redirects are followed and 4xx/5xx responses are errors by default
+            let config = ureq::Agent::config_builder();
+            let config = match user_agent {
+                Some(user_agent) => config.user_agent(user_agent),
+                None => config,
+            };
+            config.build().into()
+        };
+        HttpClient {
+            #[cfg(feature = "http")]
+            agent,
+        }
+    }
+
+    /// Streams the body of `url` into the file at `dest`, without holding all of it in memory.
+    #[cfg(feature = "http")]
+    pub fn download(&self, url: &str, dest: &Path) -> Result<()> {
+        let response = self.agent.get(url).call()?;
+        let mut file = fs::File::create(dest)?;
+        std::io::copy(&mut response.into_body().into_reader(), &mut file)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "http"))]
+    pub fn download(&self, url: &str, _dest: &Path) -> Result<()> {
+        Err(anyhow::anyhow!(
+            "{} can't be downloaded, recreate was built without the http feature",
+            url
+        ))
+    }
+}
@@ -0,0 +1,114 @@
+//! C API around [`Recreate`], enabled with the `ffi` feature. See `include/recreate.h`.
+//!
+//! Functions returning `c_int` return 0 on success and -1 on failure, in which case
+//! [`recreate_last_error`] describes what went wrong.
+
+use crate::{Args, Recreate};
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, recording its error (or panic, which must not unwind into C) for `recreate_last_error`.
+fn status(f: impl FnOnce() -> Result<()>) -> c_int {
+    let error = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return 0,
+        Ok(Err(e)) => format!("{:#}", e),
+        Err(_) => "recreate panicked".to_owned(),
+    };
+    // interior nul bytes would cut the message short, drop them instead
+    let error = CString::new(error.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+    -1
+}
+
+/// Borrows a nul-terminated UTF-8 string from C.
+///
+/// # Safety
+///
+/// `s` must be null or point to a nul-terminated string that outlives the returned borrow.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(anyhow!("{} is null", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| anyhow!("{} isn't valid UTF-8", name))
+}
+
+/// Creates an empty context, to be released with [`recreate_free`].
+///
+/// # Safety
+///
+/// Always safe to call, it is `unsafe` like the rest of the C API.
+#[no_mangle]
+pub unsafe extern "C" fn recreate_create() -> *mut Recreate {
+    Box::into_raw(Box::new(Recreate::new()))
+}
+
+/// Adds every image in the directory at `path` to the context's pool.
+///
+/// # Safety
+///
+/// `ctx` must come from [`recreate_create`] and not have been freed. `path` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn recreate_load_dir(ctx: *mut Recreate, path: *const c_char) -> c_int {
+    status(|| {
+        let recreate = ctx.as_ref().ok_or_else(|| anyhow!("ctx is null"))?;
+        let path = str_arg(path, "path")?;
+        let args = Args::library_defaults(path, "");
+        recreate.read_dir_to_vec(path, "", &args)
+    })
+}
+
+/// Builds a collage of the image at `ref_path` out of the loaded pool with a `cols`x`rows` grid, blending
+/// tiles towards their cell's color by `alpha` (0.0 to 1.0). Like the command line tool, the collage is
+/// saved as `output.png` in the reference image's directory.
+///
+/// # Safety
+///
+/// `ctx` must come from [`recreate_create`] and not have been freed. `ref_path` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn recreate_collage(
+    ctx: *mut Recreate,
+    ref_path: *const c_char,
+    cols: u32,
+    rows: u32,
+    alpha: f32,
+) -> c_int {
+    status(|| {
+        let recreate = ctx.as_ref().ok_or_else(|| anyhow!("ctx is null"))?;
+        let mut args = Args::library_defaults("", str_arg(ref_path, "ref_path")?);
+        args.cols = cols;
+        args.rows = rows;
+        args.alpha = alpha;
+        recreate.collage(&args).map(drop)
+    })
+}
+
+/// Releases a context. Passing null does nothing.
+///
+/// # Safety
+///
+/// `ctx` must be null or come from [`recreate_create`], and must not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn recreate_free(ctx: *mut Recreate) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx));
+    }
+}
+
+/// Message of the last error raised on the calling thread, or null if there has been none.
+///
+/// # Safety
+///
+/// The returned string is owned by the library and is only valid until the next failing call on this thread.
+#[no_mangle]
+pub unsafe extern "C" fn recreate_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
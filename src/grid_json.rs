@@ -0,0 +1,106 @@
+//! The tile assignment of a collage as a JSON grid, written by --output-grid-json and read back by --from-grid-json.
+//! `grid[row][col]` is `{"source": "a.jpg", "dominant": "#RRGGBB", "blend_alpha": 0.7}`, or null for a cell
+//! without a pool tile (outside the mirrored source region or without an edge).
+
+use crate::{parse_hex_color, rgba_to_hex};
+use anyhow::{anyhow, Context, Result};
+use image::Rgba;
+use serde_json::{json, Value};
+use std::fs;
+
+pub struct GridCell {
+    // file name of the pool image
+    pub source: String,
+    // dominant color of the reference cell
+    pub dominant: Rgba<u8>,
+    // alpha the tile was blended with, at the cell's centre for per-pixel alpha, not used when rendering the grid again
+    pub blend_alpha: f32,
+}
+
+pub struct TileGrid {
+    pub cols: u32,
+    pub rows: u32,
+    // row-major
+    pub cells: Vec<Option<GridCell>>,
+}
+
+impl TileGrid {
+    pub fn write(&self, path: &str) -> Result<()> {
+        let rows: Vec<Value> = self
+            .cells
+            .chunks(self.cols as usize)
+            .map(|row| {
+                row.iter()
+                    .map(|cell| match cell {
+                        Some(cell) => json!({
+                            "source": cell.source,
+                            "dominant": rgba_to_hex(cell.dominant),
+                            // rounded so 0.7 isn't written as 0.699999988
+                            "blend_alpha": (f64::from(cell.blend_alpha) * 1e4).round() / 1e4,
+                        }),
+                        None => Value::Null,
+                    })
+                    .collect()
+            })
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&rows)?)
+            .with_context(|| format!("Couldn't write tile grid in path: {}", path))
+    }
+
+    pub fn read(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read tile grid in specified path: {}", path))?;
+        let rows: Vec<Vec<Value>> = serde_json::from_str(&text)
+            .with_context(|| format!("{} isn't a JSON array of grid rows", path))?;
+
+        let cols = rows.first().map_or(0, Vec::len);
+        if cols == 0 || rows.iter().any(|row| row.len() != cols) {
+            return Err(anyhow!(
+                "The rows of tile grid {} should be non-empty and all have the same length",
+                path
+            ));
+        }
+
+        let mut cells = Vec::with_capacity(rows.len() * cols);
+        for (row_idx, row) in rows.iter().enumerate() {
+            for (col_idx, value) in row.iter().enumerate() {
+                let cell = parse_cell(value).with_context(|| {
+                    format!("Invalid cell {},{} in tile grid {}", row_idx, col_idx, path)
+                })?;
+                cells.push(cell);
+            }
+        }
+
+        Ok(Self {
+            cols: cols as u32,
+            rows: rows.len() as u32,
+            cells,
+        })
+    }
+}
+
+fn parse_cell(value: &Value) -> Result<Option<GridCell>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let field = |name: &str| {
+        value
+            .get(name)
+            .ok_or_else(|| anyhow!("missing \"{}\"", name))
+    };
+    let source = field("source")?
+        .as_str()
+        .ok_or_else(|| anyhow!("\"source\" should be a file name"))?;
+    let dominant = field("dominant")?
+        .as_str()
+        .ok_or_else(|| anyhow!("\"dominant\" should be a hex color"))?;
+    let blend_alpha = field("blend_alpha")?
+        .as_f64()
+        .ok_or_else(|| anyhow!("\"blend_alpha\" should be a number"))?;
+
+    Ok(Some(GridCell {
+        source: source.to_owned(),
+        dominant: parse_hex_color(dominant)?,
+        blend_alpha: blend_alpha as f32,
+    }))
+}
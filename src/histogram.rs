@@ -0,0 +1,141 @@
+//! Per-channel Lab color histograms, for matching tiles by their whole color distribution instead of one color,
+//! and the L* histogram matching of --histogram-match.
+
+use crate::lab_to_rgba_u8;
+use image::DynamicImage;
+use palette::{cast::from_component_slice, IntoColor, Lab, Srgb};
+
+/// Number of bins of each channel's histogram.
+pub const BINS: usize = 32;
+
+/// Number of L* bins --histogram-match maps lightness through, L* is matched to within 100 / 255.
+const MATCH_BINS: usize = 256;
+
+// Ranges of the L*, a* and b* channels the bins are spread over
+const RANGES: [(f32, f32); 3] = [(0.0, 100.0), (-128.0, 128.0), (-128.0, 128.0)];
+
+/// Normalised histograms of the L*, a* and b* channels of an image, each bin holds the share of pixels falling in it.
+#[derive(Debug, Clone)]
+pub struct LabHistogram {
+    channels: [[f32; BINS]; 3],
+}
+
+impl LabHistogram {
+    /// Histogram of an RGB [u8] buffer.
+    pub fn new(img_vec: &[u8]) -> Self {
+        let pixels = from_component_slice::<Srgb<u8>>(img_vec);
+        let mut channels = [[0.0; BINS]; 3];
+        for rgb in pixels {
+            let lab: Lab = rgb.into_format().into_color();
+            for (channel, value) in [lab.l, lab.a, lab.b].into_iter().enumerate() {
+                let (min, max) = RANGES[channel];
+                let bin = ((value - min) / (max - min) * BINS as f32) as usize;
+                channels[channel][bin.min(BINS - 1)] += 1.0;
+            }
+        }
+
+        let total = pixels.len().max(1) as f32;
+        for bin in channels.iter_mut().flatten() {
+            *bin /= total;
+        }
+        Self { channels }
+    }
+
+    /// Histogram intersection `sum(min(h1, h2)) / sum(h2)` with `other`, averaged over the channels.
+    /// 1 for identical distributions down to 0 for ones without any overlap.
+    pub fn intersection(&self, other: &LabHistogram) -> f32 {
+        let shared: f32 = self
+            .channels
+            .iter()
+            .flatten()
+            .zip(other.channels.iter().flatten())
+            .map(|(a, b)| a.min(*b))
+            .sum();
+        let total: f32 = other.channels.iter().flatten().sum();
+        if total == 0.0 {
+            0.0
+        } else {
+            shared / total
+        }
+    }
+
+    /// 1D earth mover's (Wasserstein) distance to `other`, the L1 norm of the difference of the cumulative histograms,
+    /// summed over the L* channel only or all three channels. 0 for identical distributions.
+    pub fn emd(&self, other: &LabHistogram, lightness_only: bool) -> f32 {
+        let channels = if lightness_only { 1 } else { 3 };
+        self.channels
+            .iter()
+            .zip(&other.channels)
+            .take(channels)
+            .map(|(a, b)| {
+                let mut cumulative = 0.0f32;
+                a.iter()
+                    .zip(b)
+                    .map(|(a, b)| {
+                        cumulative += a - b;
+                        cumulative.abs()
+                    })
+                    .sum::<f32>()
+            })
+            .sum()
+    }
+}
+
+/// Maps the L* of every `tile` pixel through the inverse cumulative L* distribution of `reference`, so the tile takes on
+/// the tonal range of the reference while keeping its own a*, b*, alpha and the order of its lightness values.
+pub fn histogram_match_luminance(tile: DynamicImage, reference: &DynamicImage) -> DynamicImage {
+    let mut tile = tile.into_rgba8();
+    let tile_labs: Vec<Lab> = tile
+        .pixels()
+        .map(|pixel| {
+            Srgb::new(pixel[0], pixel[1], pixel[2])
+                .into_format()
+                .into_color()
+        })
+        .collect();
+    let reference_cdf = lightness_cdf(
+        from_component_slice::<Srgb<u8>>(reference.to_rgb8().as_raw())
+            .iter()
+            .map(|rgb| rgb.into_format().into_color()),
+    );
+    let tile_cdf = lightness_cdf(tile_labs.iter().copied());
+
+    // the lightest reference bin reaching each tile bin's share of darker pixels
+    let mapping: Vec<f32> = tile_cdf
+        .iter()
+        .map(|&share| {
+            let bin = reference_cdf
+                .iter()
+                .position(|&reference_share| reference_share >= share)
+                .unwrap_or(MATCH_BINS - 1);
+            bin as f32 * 100.0 / (MATCH_BINS - 1) as f32
+        })
+        .collect();
+
+    for (pixel, lab) in tile.pixels_mut().zip(tile_labs) {
+        let l = mapping[lightness_bin(lab.l)];
+        *pixel = lab_to_rgba_u8(Lab::new(l, lab.a, lab.b), Some(pixel[3]));
+    }
+    DynamicImage::ImageRgba8(tile)
+}
+
+fn lightness_bin(l: f32) -> usize {
+    ((l / 100.0 * (MATCH_BINS - 1) as f32).round().max(0.0) as usize).min(MATCH_BINS - 1)
+}
+
+/// Share of the pixels at or below each of the `MATCH_BINS` L* bins.
+fn lightness_cdf(labs: impl Iterator<Item = Lab>) -> Vec<f32> {
+    let mut counts = vec![0u64; MATCH_BINS];
+    for lab in labs {
+        counts[lightness_bin(lab.l)] += 1;
+    }
+    let total = counts.iter().sum::<u64>().max(1) as f32;
+    let mut seen = 0;
+    counts
+        .into_iter()
+        .map(|count| {
+            seen += count;
+            seen as f32 / total
+        })
+        .collect()
+}
@@ -0,0 +1,5576 @@
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use debug::CellLabel;
+use download::HttpClient;
+use grid_json::{GridCell, TileGrid};
+use histogram::LabHistogram;
+use image::codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder};
+use image::Pixel;
+use image::{
+    imageops::FilterType, metadata::Orientation, open, DynamicImage, GenericImageView, ImageBuffer,
+    ImageDecoder, ImageEncoder, ImageFormat, ImageReader, Rgba,
+};
+use image_effects::effect::Affectable;
+use image_effects::filter::filters;
+use imageproc::definitions::Clamp;
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::gradients::sobel_gradients;
+use kmeans_colors::{get_kmeans, CentroidData, Kmeans, Sort};
+use palette::cast::from_component_slice;
+use palette::color_difference::{Ciede2000, EuclideanDistance};
+use palette::{FromColor, IntoColor, Lab, Lch, Srgb, Xyz};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use report::HtmlReportWriter;
+#[cfg(not(feature = "parallel"))]
+use sequential::{ParallelSlice, ParallelSliceMut};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Arguments};
+use std::time::Instant;
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::{Add, Mul, Range},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+    time::Duration,
+};
+use tonemap::ToneMapper;
+
+mod blend;
+mod color_transfer;
+mod debug;
+mod download;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod grid_json;
+mod histogram;
+mod manifest;
+#[cfg(feature = "nn-match")]
+mod nn;
+#[cfg(feature = "napi")]
+mod node;
+mod postprocess;
+#[cfg(feature = "python")]
+mod python;
+mod report;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(not(feature = "parallel"))]
+mod sequential;
+pub mod tonemap;
+mod watch;
+
+// k-means parameters used when calculating dominant colors
+const KMEANS_RUNS: u64 = 3;
+
+// Number of colors in the palette extracted from a --target-histogram image
+const TARGET_PALETTE_CLUSTERS: usize = 16;
+
+// Steps between replica exchanges of the anneal strategy's chains
+const SA_EXCHANGE_INTERVAL: u64 = 1000;
+// Ratio between the temperatures of neighbouring annealing chains
+const SA_CHAIN_LADDER: f32 = 2.0;
+
+// Opacity change --freq-analysis gives the tiles of overused and rarely used pool images
+const FREQ_OPACITY_STEP: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RgbaWrapper(Rgba<u8>);
+
+// Lexicographic over R, G, B, A, the order is arbitrary but lets colors be sorted and deduplicated
+impl Ord for RgbaWrapper {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0 .0.cmp(&other.0 .0)
+    }
+}
+
+impl PartialOrd for RgbaWrapper {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// impl RgbaWrapper {
+//     /// Creates a new `RgbaWrapper` from an `Rgba<u8>`.
+//     fn _new(rgba: Rgba<u8>) -> Self {
+//         RgbaWrapper(rgba)
+//     }
+// }
+
+// Implement multiplication by f32
+impl Mul<f32> for RgbaWrapper {
+    type Output = RgbaWrapper;
+
+    fn mul(self, scalar: f32) -> Self::Output {
+        let Rgba([r, g, b, a]) = self.0;
+
+        // Scale each channel and clamp between 0 and 255
+        let scaled = [
+            (r as f32 * scalar).clamp(0.0, 255.0) as u8,
+            (g as f32 * scalar).clamp(0.0, 255.0) as u8,
+            (b as f32 * scalar).clamp(0.0, 255.0) as u8,
+            a, // Keep alpha unchanged
+        ];
+
+        RgbaWrapper(Rgba(scaled))
+    }
+}
+
+// Implement addition of two RgbaWrapper instances
+// Color channels are summed, as in the weighted sums of a lerp, while alpha is the larger of the two: the result is
+// as opaque as its most opaque operand, rather than summed coverage saturating to 255 whatever the operands are
+impl Add for RgbaWrapper {
+    type Output = RgbaWrapper;
+
+    fn add(self, other: RgbaWrapper) -> Self::Output {
+        let Rgba([r1, g1, b1, a1]) = self.0;
+        let Rgba([r2, g2, b2, a2]) = other.0;
+
+        // Sum the color channels and clamp between 0 and 255
+        let summed = [
+            (r1 as u16 + r2 as u16).min(255) as u8,
+            (g1 as u16 + g2 as u16).min(255) as u8,
+            (b1 as u16 + b2 as u16).min(255) as u8,
+            a1.max(a2),
+        ];
+
+        RgbaWrapper(Rgba(summed))
+    }
+}
+
+impl RgbaWrapper {
+    /// Linear interpolation from `self` towards `other` by `t`.
+    /// Alpha is interpolated too when `lerp_alpha` is set, otherwise the larger alpha is kept like `Add` does.
+    fn lerp(self, other: RgbaWrapper, t: f32, lerp_alpha: bool) -> RgbaWrapper {
+        let alpha = (self.0[3] as f32 * (1.0 - t) + other.0[3] as f32 * t).round() as u8;
+        let mut blended = self * (1.0 - t) + other * t;
+        if lerp_alpha {
+            blended.0[3] = alpha;
+        }
+        blended
+    }
+}
+
+// Plain struct form of RgbaWrapper used for serialisation, {"r": 255, "g": 128, "b": 0, "a": 255}
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RgbaFields {
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for RgbaWrapper {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Rgba([r, g, b, a]) = self.0;
+        RgbaFields { r, g, b, a }.serialize(serializer)
+    }
+}
+
+// Deserialises from the struct form or from a hex string like "#RRGGBBAA"
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for RgbaWrapper {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Fields(RgbaFields),
+            Hex(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Fields(RgbaFields { r, g, b, a }) => Ok(RgbaWrapper(Rgba([r, g, b, a]))),
+            Repr::Hex(hex) => parse_hex_color(&hex)
+                .map(RgbaWrapper)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+// 16-bit counterpart of RgbaWrapper for --output-bit-depth 16
+struct Rgba16Wrapper(Rgba<u16>);
+
+impl Mul<f32> for Rgba16Wrapper {
+    type Output = Rgba16Wrapper;
+
+    fn mul(self, scalar: f32) -> Self::Output {
+        let Rgba([r, g, b, a]) = self.0;
+
+        // Scale each channel and clamp between 0 and 65535
+        let scaled = [
+            (r as f32 * scalar).clamp(0.0, 65535.0) as u16,
+            (g as f32 * scalar).clamp(0.0, 65535.0) as u16,
+            (b as f32 * scalar).clamp(0.0, 65535.0) as u16,
+            a, // Keep alpha unchanged
+        ];
+
+        Rgba16Wrapper(Rgba(scaled))
+    }
+}
+
+impl Add for Rgba16Wrapper {
+    type Output = Rgba16Wrapper;
+
+    fn add(self, other: Rgba16Wrapper) -> Self::Output {
+        let Rgba([r1, g1, b1, a1]) = self.0;
+        let Rgba([r2, g2, b2, a2]) = other.0;
+
+        // Sum the color channels and clamp between 0 and 65535
+        let summed = [
+            (r1 as u32 + r2 as u32).min(65535) as u16,
+            (g1 as u32 + g2 as u32).min(65535) as u16,
+            (b1 as u32 + b2 as u32).min(65535) as u16,
+            a1.max(a2),
+        ];
+
+        Rgba16Wrapper(Rgba(summed))
+    }
+}
+
+impl Rgba16Wrapper {
+    /// See `RgbaWrapper::lerp`.
+    fn lerp(self, other: Rgba16Wrapper, t: f32, lerp_alpha: bool) -> Rgba16Wrapper {
+        let alpha = (self.0[3] as f32 * (1.0 - t) + other.0[3] as f32 * t).round() as u16;
+        let mut blended = self * (1.0 - t) + other * t;
+        if lerp_alpha {
+            blended.0[3] = alpha;
+        }
+        blended
+    }
+}
+
+// The command line, either the collage options or one of the subcommands
+#[derive(Parser, Debug)]
+#[command(name = "Recreate", version="1.0", about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(flatten)]
+    args: Option<Args>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the dominant color of an image, as computed for the pool images
+    DominantColor(DominantColorArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct DominantColorArgs {
+    /// Path to the image
+    image: String,
+
+    /// Number of k-means color clusters, the dominant color is the largest one.
+    /// This is 8 by default
+    #[arg(long, default_value_t = 8)]
+    clusters: usize,
+
+    /// Maximum number of k-means iterations.
+    /// This is 20 by default
+    #[arg(long, default_value_t = 20)]
+    iterations: usize,
+
+    /// Output format, one of hex, lab, rgb or json.
+    /// Both the Lab values and the hex code are printed by default
+    #[arg(long, value_enum)]
+    format: Option<ColorFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorFormat {
+    Hex,
+    Lab,
+    Rgb,
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "Recreate", version="1.0", about, long_about = None)]
+struct Args {
+    /// Relative path to directory containing images for collage
+    #[arg(short, long)]
+    dir: String,
+
+    /// Relative path to the image to be recreated, or an HTTP(S) URL to download it from.
+    /// - reads it from standard input
+    #[arg(short = 'p', long)]
+    r#ref: String,
+
+    /// Number of columns in the collage grid
+    /// If not passed this value is set to 70 by default
+    /// Note: If need be this is usually adjusted to the nearest multiple of the reference image's width that is greater than the specified value.
+    #[arg(short, long, default_value_t = 70)]
+    cols: u32,
+
+    /// Number of columns in the collage grid
+    /// If not passed this value is set to 70 by default
+    /// Note: If need be this is usually adjusted to the nearest multiple of the reference image's height that is greater than the specified value.
+    #[arg(short, long, default_value_t = 70)]
+    rows: u32,
+
+    /// This inidates how much the images are blended to look more like the dominant color of its placement position.
+    /// Value should range from 0.0 to 1.0
+    /// If not passed this value is set to 0.7 by default
+    #[arg(short, long, default_value_t = 0.7)]
+    alpha: f32,
+
+    /// This prints info about the process running
+    /// This is true by default
+    #[arg(short, long, default_value_t = true)]
+    verbose: bool,
+
+    /// This resizes the image to a square layout using the image width. It also prevents the adjustment of specified number of grid columns and rows
+    /// This is true by default
+    #[arg(long, default_value_t = true)]
+    resize: bool,
+
+    /// This saturates each individual pixel.
+    /// This value should range from 0.0 to 0.1
+    /// Note a little change has a huge effect.
+    /// This is set to 0.05 by default
+    #[arg(short = 'x', long, default_value_t = 0.05)]
+    saturation: f32,
+
+    /// This scales up the image by specified number of times by multiplying its width and height by specified float value
+    /// Eg. If 2.5 is entered the scaled image resolution will be img_width * 2.5 x img_height * 2.5
+    /// This is 0.0 by default.
+    /// Note: 0.0 indicates no scaling is required.
+    #[arg(short, long, default_value_t = 0.0)]
+    scale: f32,
+
+    /// This scales the finished collage by multiplying its width and height by specified float value, without re-running the collage process.
+    /// Unlike --scale this is applied after the collage is assembled, so tiles can be computed at a low resolution and upscaled for display.
+    /// This is 0.0 by default.
+    /// Note: 0.0 indicates no scaling is required.
+    #[arg(long, default_value_t = 0.0)]
+    output_scale: f32,
+
+    /// This resizes the finished collage to an exact pixel resolution given as WxH, e.g. 3840x2160, after --output-scale.
+    /// How the aspect ratio of the collage is handled is set by --output-resize-mode.
+    /// This is unset by default.
+    #[arg(long, value_parser = parse_output_size)]
+    output_resize_exact: Option<OutputSize>,
+
+    /// How --output-resize-exact handles a collage with a different aspect ratio than the requested resolution.
+    /// fit keeps the aspect ratio and fits the collage inside the resolution, so one side may come out shorter.
+    /// fill keeps the aspect ratio, covers the resolution and crops the overflow around the centre.
+    /// exact stretches the collage to the resolution.
+    /// This is exact by default.
+    #[arg(long, value_enum, default_value_t = OutputResizeMode::Exact)]
+    output_resize_mode: OutputResizeMode,
+
+    /// This boosts local contrast in the reference image before it is divided into a grid, making the dominant colors of neighbouring cells more distinct.
+    /// It works like an unsharp mask with a large radius: ref_img + (ref_img - blurred_ref_img) * ref_contrast
+    /// This is 0.0 by default.
+    /// Note: 0.0 disables the enhancement.
+    #[arg(long, default_value_t = 0.0)]
+    ref_contrast: f32,
+
+    /// Gaussian blur sigma used by --ref-contrast. Larger values enhance broader regions of contrast.
+    /// This is 20.0 by default.
+    #[arg(long, default_value_t = 20.0)]
+    ref_contrast_sigma: f32,
+
+    /// This pixelates the reference image right before it is divided into a grid, averaging every block_size x block_size block to a single flat color.
+    /// Cells then have a more uniform, stable dominant color and the collage takes on a low-poly look.
+    /// This is 0 by default.
+    /// Note: 0 disables the pixelation.
+    #[arg(long, default_value_t = 0)]
+    ref_pixelate: u32,
+
+    /// Rotates the reference image clockwise by this many degrees before it is resized, scaled and divided into a grid.
+    /// Multiples of 90 turn the whole image, other angles keep its size and fill the corners with --rotate-fill-color.
+    /// This is 0.0 by default
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    rotate_reference: f32,
+
+    /// Color the corners uncovered by --rotate-reference are filled with, as a hex code (eg. #FFFFFF)
+    /// This is white by default
+    #[arg(long, value_parser = parse_hex_color, default_value = "#FFFFFF")]
+    rotate_fill_color: Rgba<u8>,
+
+    /// Preset for the dominant color k-means, the resize filter, --strategy and --refine-passes.
+    /// Options given on the command line override the preset.
+    /// This is standard by default
+    #[arg(long, value_enum, default_value_t = Profile::Standard)]
+    profile: Profile,
+
+    /// How source images are picked for each grid cell
+    /// random picks any image from the pool, best-match picks the image whose dominant color is closest to the cell's dominant color,
+    /// shuffle cycles through the pool so every image is used before any is repeated,
+    /// anneal starts from a random assignment and optimises it with simulated annealing (see --sa-iterations),
+    /// luminance-match picks the image whose mean lightness (L*) is closest to the cell's, a fast match for greyscale references
+    /// This is random by default
+    #[arg(long, value_enum, default_value_t = Strategy::Random)]
+    strategy: Strategy,
+
+    /// Number of Lab color segments the image pool is partitioned into when using the best-match strategy.
+    /// Each grid cell only searches the segment closest to its dominant color, which keeps lookups fast for very large pools.
+    /// This is set to 8 by default
+    /// Note: 0 or 1 disables partitioning and searches the whole pool.
+    #[arg(long, default_value_t = 8)]
+    pool_clusters: u32,
+
+    /// Number of extra neighbouring color segments to search alongside the closest one.
+    /// Higher values trade lookup speed for match quality.
+    /// This is set to 0 by default
+    #[arg(long, default_value_t = 0)]
+    pool_neighbour_clusters: u32,
+
+    /// How the best-match strategy compares a cell with a pool image.
+    /// centroid is the distance between their dominant colors,
+    /// histogram-intersection the overlap of their 32 bin Lab histograms, which captures the whole color distribution
+    /// and matches multicolored tiles better,
+    /// emd the earth mover's distance between the histograms, slower but more robust to multimodal distributions,
+    /// nn the cosine distance between the embeddings of the --nn-model network (needs the nn-match feature).
+    /// This is centroid by default
+    #[arg(long, value_enum, default_value_t = DistanceMetric::Centroid)]
+    distance_metric: DistanceMetric,
+
+    /// Channels --distance-metric emd compares, l for L* only (fast) or lab for all three (accurate).
+    /// This is lab by default
+    #[arg(long, value_enum, default_value_t = EmdChannels::Lab)]
+    emd_channels: EmdChannels,
+
+    /// ONNX image model whose embeddings --distance-metric nn compares, e.g. MobileNetV2 or EfficientNet-Lite.
+    /// It has to take a [N, 3, 224, 224] batch of ImageNet normalized RGB images
+    #[cfg(feature = "nn-match")]
+    #[arg(long, required_if_eq("distance_metric", "nn"))]
+    nn_model: Option<String>,
+
+    /// Makes best-match pick tiles by Maximum Marginal Relevance, trading color match for diversity.
+    /// Each cell takes the pool image maximizing lambda * sim(tile, cell) - (1 - lambda) * max sim(tile, picked tile)
+    /// over the tiles picked so far, in Lab space. 1 is plain best-match, lower values give more varied collages.
+    /// If passed without a value, 0.5 is used. Cells are then matched one after another instead of in parallel
+    #[arg(long, num_args = 0..=1, default_missing_value = "0.5")]
+    mmr_lambda: Option<f32>,
+
+    /// Assigns tiles to the cells in order of their dominant L*, darkest first, instead of row by row,
+    /// then puts them back at their grid positions. The shuffle strategy also cycles through the pool from dark to light,
+    /// so the collage follows the lightness of the reference like a gradient map
+    #[arg(long)]
+    sort_tiles_by_color: bool,
+
+    /// Minimum number of distinct source images the collage must use.
+    /// If the selected strategy uses fewer, a warning is printed and the shuffle strategy is used instead.
+    /// An error is returned if the pool (or grid) is too small to ever reach this number.
+    /// This is 0 by default, meaning no minimum.
+    #[arg(long, default_value_t = 0)]
+    min_unique_tiles: u32,
+
+    /// Path of a self-contained HTML report to write after the run.
+    /// The report embeds the collage, the dominant color palette, a grid diagram and the run statistics.
+    #[arg(long)]
+    report_html: Option<String>,
+
+    /// Path of a JSON file the run statistics are written to after the run, including the collage quality score
+    #[arg(long)]
+    stats_json: Option<String>,
+
+    /// Path of a JSON file the tile assignment is written to, as a grid of
+    /// {"source": <file name>, "dominant": <hex color>, "blend_alpha": <alpha>} cells, null for cells without a tile.
+    /// It can be rendered again with --from-grid-json
+    #[arg(long)]
+    output_grid_json: Option<String>,
+
+    /// Renders the tile grid written by --output-grid-json instead of matching tiles, so a collage can be
+    /// post-processed differently (eg. with another --alpha) without running the tile selection again.
+    /// The grid sets the columns and rows, the cells' dominant colors and their pool images, which are looked up by file name.
+    /// The cells' blend_alpha is only informational, tiles are blended with the current --alpha and --blend-mode
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "mmr_lambda",
+            "sort_tiles_by_color",
+            "ref_scale_to_fit_pool",
+            "zoom",
+            "cell_dedup_threshold",
+            "refine_passes",
+            "min_unique_tiles",
+            "equalize_pool_colors"
+        ]
+    )]
+    from_grid_json: Option<String>,
+
+    /// This keeps separate landscape and portrait pools and fills cells only with images whose shape matches the cell's aspect ratio.
+    /// This reduces the distortion caused by stretching images into cells of a different shape.
+    #[arg(long)]
+    split_by_aspect: bool,
+
+    /// This subsamples the pool to spread its dominant colors evenly over Lab space before tiles are matched.
+    /// Images are picked greedily, each one the farthest from the colors already picked (farthest-point sampling),
+    /// so a pool of mostly similar images doesn't end up filling most of the cells.
+    #[arg(long)]
+    equalize_pool_colors: bool,
+
+    /// Number of pool images --equalize-pool-colors keeps.
+    /// This is half the pool by default
+    #[arg(long, requires = "equalize_pool_colors", value_parser = clap::value_parser!(u32).range(1..))]
+    equalize_pool_size: Option<u32>,
+
+    /// Converts source images with an embedded ICC profile (eg. AdobeRGB or ProPhoto JPEGs) to sRGB when they are loaded,
+    /// so their colors are matched correctly. Images without a profile are used as they are
+    #[arg(long)]
+    respect_icc_profile: bool,
+
+    /// Keeps going when pool images in --dir or --manifest fail to load, building the collage from the ones that did.
+    /// Without it the failures are returned as an error
+    #[arg(long)]
+    tolerate_errors: bool,
+
+    /// This removes the alpha channel from source images right after loading them.
+    /// Transparent areas are filled with --alpha-fill instead of showing up black in the collage.
+    #[arg(long)]
+    strip_alpha: bool,
+
+    /// Color used to fill transparent areas when --strip-alpha is set, as a hex code (eg. #FFFFFF)
+    /// This is white by default
+    #[arg(long, value_parser = parse_hex_color, default_value = "#FFFFFF")]
+    alpha_fill: Rgba<u8>,
+
+    /// Channels written to the output image, rgb drops the alpha channel.
+    /// This is rgba by default
+    #[arg(long, value_enum, default_value_t = OutputChannels::Rgba)]
+    output_channels: OutputChannels,
+
+    /// Custom 3x3 convolution kernel applied to the output image before it is saved, as nine comma separated values in row-major order.
+    /// Eg. 0,-1,0,-1,5,-1,0,-1,0 sharpens the image and -2,-1,0,-1,1,1,0,1,2 embosses it
+    #[arg(long, value_parser = parse_kernel, allow_hyphen_values = true)]
+    kernel: Option<[f32; 9]>,
+
+    /// Value each convolved pixel is divided by when --kernel is set.
+    /// If not passed this is the sum of the kernel values (or 1.0 when they sum to 0.0)
+    #[arg(long)]
+    kernel_divisor: Option<f32>,
+
+    /// Rotates the hue of every output pixel by this many degrees, from 0 to 360, around the LCh color wheel.
+    /// Lightness and chroma are kept, so this shifts every color of the collage alike. Applied before --kernel.
+    /// This is 0.0 by default
+    #[arg(long, default_value_t = 0.0)]
+    hue_rotate: f32,
+
+    /// Reduces the output image to at most this many colors, from 2 to 256, with a median cut palette.
+    /// Every pixel takes its nearest palette color and png outputs are saved as palette-indexed pngs,
+    /// which suits pixel art style collages. Applied last, after --flatten and --output-channels
+    #[arg(long)]
+    palette_quantize: Option<u32>,
+
+    /// Applies Floyd-Steinberg dithering when --palette-quantize or --cell-quantize maps the pixels to their palette,
+    /// so smooth gradients mix neighbouring palette colors instead of banding
+    #[arg(long)]
+    dither: bool,
+
+    /// Reduces every pool image to this many colors, from 2 to 8, with a median cut palette after it is resized to its cell,
+    /// so the quantized tile is what gets blended with the dominant color. Gives collages a poster or retro look,
+    /// with --alpha 0.0 a purely color quantized mosaic
+    #[arg(long, value_parser = clap::value_parser!(u8).range(2..=8))]
+    cell_quantize: Option<u8>,
+
+    /// Matches the L* histogram of every tile to the histogram of its reference cell before blending, while keeping the
+    /// tile's a* and b*, so tiles take on the exact tonal range of their cell and keep their own internal contrast.
+    /// With --alpha 0.0 the matched tiles are placed without blending towards the dominant color
+    #[arg(long)]
+    histogram_match: bool,
+
+    /// Transfers the mean and standard deviation of every Lab channel of the reference cell onto its tile before blending
+    /// (Reinhard et al.'s color transfer), a richer alternative to blending towards one color that keeps the tile's
+    /// internal contrast while matching the overall color of its cell.
+    /// With --alpha 0.0 the transferred tiles are placed without blending towards the dominant color
+    #[arg(long, conflicts_with = "histogram_match")]
+    color_transfer: bool,
+
+    /// Scales the standard deviation of every Lab channel of each tile to that of its reference cell before blending,
+    /// without moving the channel means as --color-transfer does, so tiles keep their own colors and take on the contrast of their cell
+    #[arg(long, conflicts_with_all = ["histogram_match", "color_transfer"])]
+    contrast_transfer: bool,
+
+    /// Relative path to a grayscale image used as a per-pixel alpha instead of --alpha.
+    /// Each pixel's luminance maps to the blend at the same collage position: black shows only the tile, white only the dominant color.
+    /// The mask is resized to the collage dimensions using nearest-neighbour interpolation.
+    #[arg(long)]
+    alpha_mask: Option<String>,
+
+    /// Varies --alpha per tile with the distance of the cell from the image centre, eased by this curve.
+    /// The effective alpha is alpha * curve(distance), with the distance going from 0 at the centre to 1 in the corners,
+    /// so with ease-in centre tiles show more of the tile image and edge tiles more of the dominant color.
+    /// One of linear, ease-in, ease-out, ease-in-out or custom-bezier (see --tile-blend-bezier)
+    #[arg(long, value_enum, conflicts_with = "alpha_mask")]
+    tile_blend_curve: Option<BlendCurve>,
+
+    /// Control points x1,y1,x2,y2 of the cubic bezier used by --tile-blend-curve custom-bezier, like CSS cubic-bezier()
+    /// eg. 0.42,0,0.58,1
+    #[arg(long, value_parser = parse_bezier, required_if_eq("tile_blend_curve", "custom-bezier"))]
+    tile_blend_bezier: Option<[f32; 4]>,
+
+    /// Pixels of padding on every side of each tile, filled with the cell's dominant color for a polaroid frame look.
+    /// The pool image is resized to fit inside the padding and centred in the cell.
+    /// Padding is capped so at least one pixel of the image is left.
+    /// This is 0 by default
+    #[arg(long, default_value_t = 0)]
+    cell_padding: u32,
+
+    /// Comma separated list of formats the collage is saved in, eg. png,jpeg,webp
+    /// All formats are written in the same run, named output.png, output.jpg and output.webp
+    /// This is png by default
+    #[arg(long, value_enum, value_delimiter = ',', num_args = 1.., default_value = "png")]
+    output_formats: Vec<OutputFormat>,
+
+    /// Comma separated list of strategies to build the collage with, eg. random,best-match,shuffle, instead of --strategy.
+    /// Each collage is saved as output_<strategy>, sharing the loaded pool and its dominant colors, and
+    /// output_compare.png shows them side by side with their names
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        conflicts_with_all = ["strategy", "output", "from_grid_json", "output_grid_json", "stats_json", "report_html"]
+    )]
+    compare_strategies: Vec<Strategy>,
+
+    /// Path the collage is saved to instead of output.<format> next to the reference image, in the --output-formats format.
+    /// - writes it to standard output, progress messages then go to standard error
+    #[arg(long)]
+    output: Option<String>,
+
+    /// This scales the reference image (and its grid) down when the grid has more cells than the pool can fill without using any image more than --max-reuse times.
+    /// The output can end up smaller than requested, a warning explains the scaling when this happens.
+    #[arg(long)]
+    ref_scale_to_fit_pool: bool,
+
+    /// Maximum number of times each pool image should be used, see --ref-scale-to-fit-pool and --auto-grid
+    /// This is set to 1 by default
+    #[arg(long, default_value_t = 1)]
+    max_reuse: u32,
+
+    /// Computes the grid from the pool instead of --cols and --rows: grid_cols * grid_rows is the pool size times --max-reuse,
+    /// so each pool image can be used exactly --max-reuse times, split into the columns and rows closest to the reference's aspect ratio.
+    /// Like --cols and --rows, the grid is then adjusted to divide the reference image if need be
+    #[arg(long, conflicts_with_all = ["cols", "rows", "from_grid_json"])]
+    auto_grid: bool,
+
+    /// This builds only part of the collage and mirrors it to fill the rest, for a symmetrical output.
+    /// horizontal builds the left half and mirrors it onto the right, vertical builds the top half and mirrors it onto the bottom,
+    /// quad builds the top-left quadrant and mirrors it four ways.
+    /// Note: this also cuts processing time by 2x (or 4x for quad).
+    #[arg(long, value_enum)]
+    mirror: Option<Mirror>,
+
+    /// Collages a sub-region of the reference at a higher grid density, given as x,y,w,h,scale_factor in collage pixel coordinates.
+    /// The region is cropped, scaled up by scale_factor and tiled with a proportionally finer grid, while the rest of the image is tiled at the regular density.
+    /// Eg. 200,150,300,300,3 tiles a 300x300 region at (200, 150) with cells 3 times smaller than the rest.
+    /// Can't be combined with --mirror
+    #[arg(long, value_parser = parse_zoom)]
+    zoom: Option<Zoom>,
+
+    /// This writes a debug_grid.png next to the output where every cell is outlined and labelled with
+    /// the file name of the source image placed there and the hex code of its dominant color.
+    #[arg(long)]
+    debug_grid: bool,
+
+    /// This writes a debug_indices.png next to the output, with the same dimensions as the collage,
+    /// where every cell is numbered by its flat index (0-based, row-major).
+    #[arg(long)]
+    debug_indices: bool,
+
+    /// This writes a distances_heatmap.png next to the output, with the same dimensions as the collage, where every cell
+    /// is colored by the Lab distance between its dominant color and the dominant color of the tile placed there:
+    /// green for a good match up to red for a Lab distance of 50 or more, showing which regions the pool covers poorly
+    #[arg(long)]
+    visualise_distances: bool,
+
+    /// This writes a ref_grid.png next to the output showing the (resized/scaled) reference image with 1px lines at every cell boundary.
+    /// Each cell's lines are drawn in the hue of its dominant color, which shows where the adjusted grid actually falls.
+    #[arg(long)]
+    ref_grid_overlay: bool,
+
+    /// Draws lines along the tile boundaries of the final collage, given as <color>,<width> eg. #FFFFFF80,2
+    /// The color is a hex code which may include an alpha value, the width is in output pixels.
+    /// If passed without a value, 1px white lines at 50% opacity are drawn.
+    #[arg(long, value_parser = parse_grid_lines, num_args = 0..=1, default_missing_value = "#FFFFFF80,1")]
+    grid_lines: Option<GridLines>,
+
+    /// The color each tile is blended towards.
+    /// normal blends towards the cell's dominant color, complementary blends towards its Lab complement
+    /// which gives strongly contrasting, pop-art style tiles.
+    /// This is normal by default
+    #[arg(long, value_enum, default_value_t = BlendMode::Normal)]
+    blend_mode: BlendMode,
+
+    /// This inverts the colors of every pool image right before it is resized and blended into its cell, so the inverted image is what gets blended.
+    /// Tiles are still matched by the colors of the original images
+    #[arg(long)]
+    invert_tiles: bool,
+
+    /// This flips the tiles of even columns horizontally and the tiles of even rows vertically, counting from 0.
+    /// Neighbouring tiles of the same pool image then mirror each other at their shared edge, so repeated tiles join
+    /// seamlessly in pattern-like collages. The flips depend only on the grid position
+    #[arg(long)]
+    tile_mirror_at_edges: bool,
+
+    /// Shows how evenly the pool was used by scaling the opacity (alpha channel) of the finished tiles:
+    /// tiles of pool images used more than twice as often as the average image get 10% more transparent,
+    /// tiles of images used less than half as often 10% more opaque, drawing attention to the rarest images
+    #[arg(long)]
+    freq_analysis: bool,
+
+    /// How the color of each grid cell and pool image is computed, which tiles are matched by and blended towards.
+    /// dominant is the largest of the k-means color clusters (see --profile),
+    /// mean is the average of all pixels, a lot faster but muddier for busy images,
+    /// median is the per-channel median of all pixels, as fast as mean for small cells and less pulled by highlights and shadows.
+    /// This is dominant by default
+    #[arg(long, value_enum, default_value_t = BlendTarget::Dominant)]
+    blend_target: BlendTarget,
+
+    /// Maximum number of pixels of a cell or pool image the dominant color k-means runs on, larger ones are randomly sub-sampled.
+    /// About 1000 pixels are usually enough for stable dominant colors.
+    /// This is 0 (all pixels) by default
+    #[arg(long, default_value_t = 0)]
+    dominant_sample_size: u32,
+
+    /// Bits per channel of the output image, either 8 or 16.
+    /// In 16 bit mode tiles are blended with 16 bit precision and the output is saved as a 16 bit png,
+    /// this can't be combined with --zoom, --kernel, --hue-rotate, --palette-quantize, --cell-quantize, --histogram-match, --color-transfer, --contrast-transfer, --grid-lines or non png output formats.
+    /// This is 8 by default
+    #[arg(long, value_enum, default_value_t = OutputBitDepth::Eight)]
+    output_bit_depth: OutputBitDepth,
+
+    /// Path to an ICC profile file to embed in the output image(s)
+    #[arg(long, conflicts_with = "icc_preset")]
+    icc_profile: Option<String>,
+
+    /// Standard ICC profile to embed in the output image(s), one of srgb, adobe-rgb or display-p3.
+    /// Note that this only tags the output, pixel values are not converted
+    #[arg(long, value_enum)]
+    icc_preset: Option<IccPreset>,
+
+    /// Composites the finished collage over a solid background color (hex code) so the output is fully opaque.
+    /// Unlike --strip-alpha this works on the output rather than the pool images
+    #[arg(long, value_parser = parse_hex_color)]
+    flatten: Option<Rgba<u8>>,
+
+    /// Blends the alpha channel of each tile towards the average alpha of its reference cell, by the same weight as the color.
+    /// Without this the larger of the tile's and the (opaque) dominant color's alpha is kept, which keeps tiles opaque
+    #[arg(long)]
+    lerp_alpha: bool,
+
+    /// Cells whose dominant color is within this Lab distance of an earlier cell reuse that cell's tile
+    /// instead of selecting, resizing and blending a new one. Speeds up references with large flat areas.
+    /// This is 0.0 (disabled) by default
+    #[arg(long, default_value_t = 0.0)]
+    cell_dedup_threshold: f32,
+
+    /// Only fills cells that contain an edge with a tile, every other cell keeps the reference image's pixels.
+    /// Edges are found with a Sobel filter, see --edge-threshold
+    #[arg(long)]
+    ref_edges_only: bool,
+
+    /// Sobel gradient magnitude a pixel needs to count as an edge for --ref-edges-only.
+    /// This is 128 by default
+    #[arg(long, default_value_t = 128)]
+    edge_threshold: u16,
+
+    /// Number of hill climbing passes run after the tiles are assigned, each pass swaps the tiles of neighbouring
+    /// cells whenever that brings both closer to their cell's dominant color.
+    /// This is 0 (disabled) by default
+    #[arg(long, default_value_t = 0)]
+    refine_passes: u32,
+
+    /// Number of steps the anneal strategy runs for.
+    /// This is 10000 by default
+    #[arg(long, default_value_t = 10_000)]
+    sa_iterations: u64,
+
+    /// Temperature the anneal strategy starts at, in CIEDE2000 distance units.
+    /// Higher values accept more changes that make the result worse early on.
+    /// This is 10.0 by default
+    #[arg(long, default_value_t = 10.0)]
+    sa_start_temp: f32,
+
+    /// Temperature the anneal strategy ends at, in CIEDE2000 distance units.
+    /// This is 0.01 by default
+    #[arg(long, default_value_t = 0.01)]
+    sa_end_temp: f32,
+
+    /// Number of annealing chains the anneal strategy runs in parallel, each one twice as hot as the previous.
+    /// Neighbouring chains periodically swap configurations (parallel tempering) and the best chain wins.
+    /// This is 4 by default
+    #[arg(long, default_value_t = 4)]
+    sa_chains: u32,
+
+    /// Seed for the random number generators, runs with the same seed and settings pick the same tiles
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Shuffles the pool once after loading, with --seed if given, so the shuffle strategy and ties in matching
+    /// don't favour the images loaded first (which are in file name order)
+    #[arg(long)]
+    shuffle_pool_order: bool,
+
+    /// Number of threads the pool is loaded and the collage is built with.
+    /// Defaults to 20 for loading the pool and one per CPU core for building
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    threads: Option<u16>,
+
+    /// Makes runs with the same settings produce bit-identical output on the same machine:
+    /// --seed defaults to 0 and everything runs on a single thread (--threads 1).
+    /// Floating-point results may still differ between platforms and builds
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Tone mapping operator used to bring HDR (.hdr, .exr) images down to 8 bit, one of reinhard, aces or filmic.
+    /// This is reinhard by default
+    #[arg(long, value_enum, default_value_t = Tonemapping::Reinhard)]
+    tonemapping: Tonemapping,
+
+    /// Treats the reference as an HDR (.hdr or .exr) image, it's tone mapped with --tonemapping
+    /// before the grid and dominant colors are computed.
+    /// The output is still 8 bit unless --output-bit-depth 16 is set
+    #[arg(long)]
+    ref_hdr: bool,
+
+    /// Path of a CSV file to export metadata of every pool image to after loading it (size, dominant color,
+    /// luminance and contrast), handy for curating the pool in a spreadsheet
+    #[arg(long)]
+    pool_stats_csv: Option<String>,
+
+    /// Path of a CSV file selecting which pool images to load, only files listed in its filename column with
+    /// include set to true are used. A --pool-stats-csv export with an include column added works as a starting point
+    #[arg(long)]
+    pool_filter_csv: Option<String>,
+
+    /// Path to a palette image whose color histogram the collage is graded towards.
+    /// Tiles are still picked by their cell's dominant color, but are blended towards a color from the palette,
+    /// with each palette color used for about its share of the cells
+    #[arg(long)]
+    target_histogram: Option<String>,
+
+    /// A second pool directory and a Lab lightness (L*, 0-100) threshold, given as dir,threshold.
+    /// Cells whose dominant color is darker than the threshold are filled from this pool, the rest from --dir.
+    /// Can't be combined with the anneal strategy or --refine-passes
+    #[arg(long, value_parser = parse_mix_pool)]
+    mix_pool: Option<MixPool>,
+
+    /// S3 bucket to load the pool images from instead of a local directory, objects are downloaded into --dir first.
+    /// Credentials are resolved from the standard AWS environment variables and files
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    s3_bucket: Option<String>,
+
+    /// Only objects whose key starts with this prefix are loaded from --s3-bucket
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "", requires = "s3_bucket")]
+    s3_prefix: String,
+
+    /// Region of --s3-bucket, defaults to the one of the AWS configuration
+    #[cfg(feature = "s3")]
+    #[arg(long, requires = "s3_bucket")]
+    s3_region: Option<String>,
+
+    /// JSON manifest listing more pool images, as an array of paths (relative to the manifest) and HTTP(S) URLs.
+    /// They are added to the images of --dir, URLs are downloaded into --manifest-cache-dir first.
+    #[arg(long)]
+    manifest: Option<String>,
+
+    /// Directory images downloaded for --manifest are cached in, so they are only downloaded once.
+    /// Defaults to recreate-manifest in --temp-dir
+    #[arg(long)]
+    manifest_cache_dir: Option<String>,
+
+    /// User-Agent header sent when downloading --manifest images
+    #[arg(long)]
+    manifest_user_agent: Option<String>,
+
+    /// Keeps running after the collage is built and rebuilds it whenever the reference image changes or images are added to --dir.
+    /// New images are added to the pool without reloading the rest.
+    #[arg(long)]
+    watch: bool,
+
+    /// Milliseconds without further filesystem changes to wait for before rebuilding in --watch mode
+    #[arg(long, default_value_t = 500)]
+    watch_debounce: u64,
+
+    /// Leaves temporary files in place after the run instead of removing them, for inspecting them afterwards:
+    /// downloaded --ref images and partial --manifest downloads
+    #[arg(long)]
+    keep_temp: bool,
+
+    /// Directory temporary files are written to, defaults to the system's temporary directory
+    #[arg(long)]
+    temp_dir: Option<String>,
+}
+
+// Every option at its command line default, with an empty --dir and --ref.
+// The values come from parsing, so they can't drift from the clap attributes.
+impl Default for Args {
+    fn default() -> Self {
+        Args::try_parse_from(["recreate", "--dir", "", "--ref", ""])
+            .expect("the command line defaults should parse")
+    }
+}
+
+impl Args {
+    /// Arguments for library callers: the given pool directory and reference image with every other
+    /// option at its command line default, and nothing printed.
+    fn library_defaults(dir: &str, r#ref: &str) -> Args {
+        Args {
+            dir: dir.to_owned(),
+            r#ref: r#ref.to_owned(),
+            verbose: false,
+            ..Args::default()
+        }
+    }
+
+    /// Whether tiles are matched by comparing histograms, with best-match and a histogram --distance-metric.
+    fn matches_histograms(&self) -> bool {
+        self.strategy == Strategy::BestMatch && self.distance_metric.uses_histograms()
+    }
+
+    /// Whether best-match compares --nn-model embeddings.
+    #[cfg(feature = "nn-match")]
+    fn matches_embeddings(&self) -> bool {
+        self.strategy == Strategy::BestMatch && self.distance_metric == DistanceMetric::Nn
+    }
+
+    /// Whether the collage needs the dominant color of every pool image, for matching, refining or its quality score.
+    fn uses_dominant_colors(&self) -> bool {
+        // a --from-grid-json grid already has its tiles and skips the score, only its heat map compares colors
+        self.visualise_distances
+            || self.from_grid_json.is_none()
+                && (matches!(self.strategy, Strategy::BestMatch | Strategy::Anneal)
+                    || self.refine_passes > 0
+                    || self.equalize_pool_colors
+                    || self.stats_json.is_some())
+    }
+
+    /// How tiles are matched to their reference cell before blending, with --histogram-match, --color-transfer or --contrast-transfer.
+    fn tile_match(&self) -> Option<TileMatch> {
+        if self.histogram_match {
+            Some(TileMatch::Histogram)
+        } else if self.color_transfer {
+            Some(TileMatch::ColorTransfer)
+        } else if self.contrast_transfer {
+            Some(TileMatch::Contrast)
+        } else {
+            None
+        }
+    }
+
+    /// The options the colors of cells and pool images are computed with.
+    fn color_settings(&self) -> ColorSettings {
+        ColorSettings {
+            blend_target: self.blend_target,
+            dominant_sample_size: self.dominant_sample_size,
+            profile: self.profile,
+        }
+    }
+
+    /// Whether the collage needs the mean lightness of every pool image.
+    fn uses_mean_lightness(&self) -> bool {
+        self.from_grid_json.is_none()
+            && (self.strategy == Strategy::LuminanceMatch || self.sort_tiles_by_color)
+    }
+
+    /// Applies the --strategy and --refine-passes of --profile unless they were given on the command line.
+    fn apply_profile(&mut self, matches: &ArgMatches) {
+        let from_command_line = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let (strategy, refine_passes) = match self.profile {
+            Profile::Draft => (Strategy::Random, 0),
+            Profile::Standard => return,
+            Profile::Quality => (Strategy::BestMatch, 2),
+        };
+        if !from_command_line("strategy") {
+            self.strategy = strategy;
+        }
+        if !from_command_line("refine_passes") {
+            self.refine_passes = refine_passes;
+        }
+    }
+
+    /// Directory temporary files go in, --temp-dir or the system's temporary directory.
+    fn temp_dir(&self) -> PathBuf {
+        self.temp_dir
+            .as_ref()
+            .map_or_else(std::env::temp_dir, PathBuf::from)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputBitDepth {
+    #[value(name = "8")]
+    Eight,
+    #[value(name = "16")]
+    Sixteen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Tonemapping {
+    Reinhard,
+    Aces,
+    Filmic,
+}
+
+impl Tonemapping {
+    fn mapper(self) -> &'static dyn ToneMapper {
+        match self {
+            Tonemapping::Reinhard => &tonemap::Reinhard,
+            Tonemapping::Aces => &tonemap::Aces,
+            Tonemapping::Filmic => &tonemap::Filmic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BlendMode {
+    Normal,
+    Complementary,
+}
+
+/// Adjustment of a tile towards the pixels of its reference cell, applied before blending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TileMatch {
+    Histogram,
+    ColorTransfer,
+    Contrast,
+}
+
+impl TileMatch {
+    fn apply(self, tile: DynamicImage, reference: &DynamicImage) -> DynamicImage {
+        match self {
+            TileMatch::Histogram => histogram::histogram_match_luminance(tile, reference),
+            TileMatch::ColorTransfer => color_transfer::transfer_tile(tile, reference),
+            TileMatch::Contrast => blend::contrast_match(tile, reference),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DistanceMetric {
+    Centroid,
+    HistogramIntersection,
+    Emd,
+    #[cfg(feature = "nn-match")]
+    Nn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EmdChannels {
+    L,
+    Lab,
+}
+
+impl DistanceMetric {
+    /// Whether the metric compares histograms, which then have to be computed for the cells and pool images.
+    fn uses_histograms(self) -> bool {
+        matches!(
+            self,
+            DistanceMetric::HistogramIntersection | DistanceMetric::Emd
+        )
+    }
+}
+
+/// What the --distance-metric options besides centroid compare of each grid cell, left empty when unused.
+#[derive(Default)]
+struct CellFeatures {
+    histograms: Vec<LabHistogram>,
+    #[cfg(feature = "nn-match")]
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl CellFeatures {
+    /// Features of the cells in `order`, which lists cell indices.
+    fn permuted(&self, order: &[usize]) -> CellFeatures {
+        let permute = |values: &[LabHistogram]| match values {
+            [] => Vec::new(),
+            values => order.iter().map(|&idx| values[idx].clone()).collect(),
+        };
+        CellFeatures {
+            histograms: permute(&self.histograms),
+            #[cfg(feature = "nn-match")]
+            embeddings: match self.embeddings.as_slice() {
+                [] => Vec::new(),
+                values => order.iter().map(|&idx| values[idx].clone()).collect(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BlendCurve {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    CustomBezier,
+}
+
+impl BlendCurve {
+    /// Eases `t` in the 0-1 range, `bezier` holds the control points of custom-bezier.
+    fn ease(self, t: f32, bezier: Option<[f32; 4]>) -> f32 {
+        match self {
+            BlendCurve::Linear => t,
+            BlendCurve::EaseIn => t * t,
+            BlendCurve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            BlendCurve::EaseInOut => t * t * (3.0 - 2.0 * t),
+            BlendCurve::CustomBezier => {
+                cubic_bezier(bezier.expect("clap requires --tile-blend-bezier"), t)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BlendTarget {
+    Dominant,
+    Mean,
+    Median,
+}
+
+#[derive(Debug, Clone)]
+struct MixPool {
+    dir: String,
+    threshold: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GridLines {
+    color: Rgba<u8>,
+    width: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Zoom {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    factor: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OutputSize {
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputResizeMode {
+    Fit,
+    Fill,
+    Exact,
+}
+
+impl OutputResizeMode {
+    /// Resizes `img` to `size` according to the mode.
+    fn apply<P: image::Pixel + 'static>(
+        self,
+        img: &ImageBuffer<P, Vec<P::Subpixel>>,
+        size: OutputSize,
+        filter: FilterType,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let (width, height) = img.dimensions();
+        let scale_x = size.width as f64 / width as f64;
+        let scale_y = size.height as f64 / height as f64;
+        let scaled = |scale: f64| {
+            (
+                ((width as f64 * scale).round() as u32).max(1),
+                ((height as f64 * scale).round() as u32).max(1),
+            )
+        };
+
+        match self {
+            OutputResizeMode::Exact => {
+                image::imageops::resize(img, size.width, size.height, filter)
+            }
+            OutputResizeMode::Fit => {
+                let (new_width, new_height) = scaled(scale_x.min(scale_y));
+                image::imageops::resize(
+                    img,
+                    new_width.min(size.width),
+                    new_height.min(size.height),
+                    filter,
+                )
+            }
+            OutputResizeMode::Fill => {
+                let (new_width, new_height) = scaled(scale_x.max(scale_y));
+                let (new_width, new_height) =
+                    (new_width.max(size.width), new_height.max(size.height));
+                let resized = image::imageops::resize(img, new_width, new_height, filter);
+                image::imageops::crop_imm(
+                    &resized,
+                    (new_width - size.width) / 2,
+                    (new_height - size.height) / 2,
+                    size.width,
+                    size.height,
+                )
+                .to_image()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mirror {
+    Horizontal,
+    Vertical,
+    Quad,
+}
+
+impl Mirror {
+    fn mirrors_x(self) -> bool {
+        matches!(self, Mirror::Horizontal | Mirror::Quad)
+    }
+
+    fn mirrors_y(self) -> bool {
+        matches!(self, Mirror::Vertical | Mirror::Quad)
+    }
+
+    /// Whether the cell at grid position (x, y) is in the half/quadrant that is actually built.
+    fn is_source_cell(self, x: u32, y: u32, cols: u32, rows: u32) -> bool {
+        (!self.mirrors_x() || x < cols.div_ceil(2)) && (!self.mirrors_y() || y < rows.div_ceil(2))
+    }
+
+    /// Copies the built half/quadrant of `buffer` onto the remaining area.
+    fn apply<P: image::Pixel>(self, buffer: &mut ImageBuffer<P, Vec<P::Subpixel>>) {
+        let (width, height) = buffer.dimensions();
+
+        if self.mirrors_x() {
+            for y in 0..height {
+                for x in 0..width / 2 {
+                    let pixel = *buffer.get_pixel(x, y);
+                    buffer.put_pixel(width - 1 - x, y, pixel);
+                }
+            }
+        }
+
+        // runs after the horizontal pass so quad also fills the bottom-right quadrant
+        if self.mirrors_y() {
+            for y in 0..height / 2 {
+                for x in 0..width {
+                    let pixel = *buffer.get_pixel(x, y);
+                    buffer.put_pixel(x, height - 1 - y, pixel);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    /// Encodes `img` into `writer` in this format, embedding `icc_profile` if one is given.
+    fn write(
+        self,
+        img: &DynamicImage,
+        writer: impl io::Write,
+        icc_profile: Option<&[u8]>,
+    ) -> Result<()> {
+        match self {
+            OutputFormat::Png => encode(PngEncoder::new(writer), img, icc_profile),
+            // jpeg has no alpha channel
+            OutputFormat::Jpeg => encode(
+                JpegEncoder::new(writer),
+                &DynamicImage::ImageRgb8(img.to_rgb8()),
+                icc_profile,
+            ),
+            OutputFormat::Webp => encode(WebPEncoder::new_lossless(writer), img, icc_profile),
+        }
+    }
+}
+
+fn encode(
+    mut encoder: impl ImageEncoder,
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+) -> Result<()> {
+    if let Some(profile) = icc_profile {
+        encoder.set_icc_profile(profile.to_vec())?;
+    }
+    img.write_with_encoder(encoder)?;
+    Ok(())
+}
+
+// Minimal matrix/TRC ICC v2 profiles built from each standard's published primaries, white point and
+// transfer curve, adapted to D50. They carry no copyright.
+const SRGB_ICC: &[u8] = include_bytes!("../assets/icc/sRGB.icc");
+const ADOBE_RGB_ICC: &[u8] = include_bytes!("../assets/icc/AdobeRGB.icc");
+const DISPLAY_P3_ICC: &[u8] = include_bytes!("../assets/icc/DisplayP3.icc");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IccPreset {
+    Srgb,
+    AdobeRgb,
+    DisplayP3,
+}
+
+impl IccPreset {
+    fn profile(self) -> &'static [u8] {
+        match self {
+            IccPreset::Srgb => SRGB_ICC,
+            IccPreset::AdobeRgb => ADOBE_RGB_ICC,
+            IccPreset::DisplayP3 => DISPLAY_P3_ICC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputChannels {
+    Rgb,
+    Rgba,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Profile {
+    /// Fast previews: 2 k-means clusters, 5 iterations, tolerance 20, nearest neighbour resizing and the random strategy
+    Draft,
+    /// The defaults: 8 k-means clusters, 20 iterations, tolerance 5, Catmull-Rom and Lanczos3 resizing
+    Standard,
+    /// Slow but accurate: 16 k-means clusters, 50 iterations, tolerance 1, Lanczos3 resizing, the best-match strategy
+    /// and 2 --refine-passes
+    Quality,
+}
+
+impl Profile {
+    /// Settings of the k-means that finds dominant colors.
+    fn kmeans(self) -> KmeansParams {
+        let (clusters, max_iter, converge) = match self {
+            Profile::Draft => (2, 5, 20.0),
+            Profile::Standard => (8, 20, 5.0),
+            Profile::Quality => (16, 50, 1.0),
+        };
+        KmeansParams {
+            clusters,
+            max_iter,
+            converge,
+        }
+    }
+
+    /// Resize filter used where `standard` is used by default.
+    fn filter(self, standard: FilterType) -> FilterType {
+        match self {
+            Profile::Draft => FilterType::Nearest,
+            Profile::Standard => standard,
+            Profile::Quality => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// The options `cell_color` depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ColorSettings {
+    blend_target: BlendTarget,
+    dominant_sample_size: u32,
+    profile: Profile,
+}
+
+/// Parameters of a k-means color clustering.
+#[derive(Debug, Clone, Copy)]
+struct KmeansParams {
+    clusters: usize,
+    max_iter: usize,
+    converge: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Strategy {
+    Random,
+    BestMatch,
+    Shuffle,
+    Anneal,
+    LuminanceMatch,
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // use the same kebab-case names accepted on the command line
+        let value = self.to_possible_value().unwrap();
+        f.write_str(value.get_name())
+    }
+}
+
+// Set when the collage itself is written to standard output, progress messages then go to standard error
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints a progress message.
+fn write_status(args: Arguments) {
+    if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+// Like println!, but follows STATUS_TO_STDERR
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::write_status(format_args!($($arg)*));
+    };
+}
+pub(crate) use status;
+
+fn print_if(determiner: bool, args: Arguments) {
+    if determiner {
+        write_status(args);
+    }
+}
+
+// A helper macro to make it more ergonomic to use, similar to println!
+macro_rules! print_if {
+    ($determiner:expr, $($arg:tt)*) => {
+        print_if($determiner, format_args!($($arg)*));
+    };
+}
+
+/// State of one simulated annealing chain of the anneal strategy.
+struct AnnealChain {
+    assignment: Vec<usize>,
+    energy: f32,
+    rng: StdRng,
+    // multiple of the base temperature schedule this chain runs at
+    temperature_scale: f32,
+}
+
+/// Summary of a finished collage run, used for reporting.
+#[derive(Debug)]
+struct CollageStats {
+    ref_path: String,
+    output_path: String,
+    width: u32,
+    height: u32,
+    grid_cols: u32,
+    grid_rows: u32,
+    pool_size: usize,
+    strategy: Strategy,
+    alpha: f32,
+    kmeans: KmeansParams,
+    // Summed CIEDE2000 distance of the tile cells' colors to their tiles', lower is better.
+    // Only computed with --verbose or --stats-json
+    quality_score: Option<f32>,
+    // Dominant color of each grid cell in row-major order
+    cell_colors: Vec<Rgba<u8>>,
+}
+
+/// A built collage, before it is saved.
+struct Collage {
+    image: DynamicImage,
+    grid_cols: u32,
+    grid_rows: u32,
+    // Dominant color of each grid cell in row-major order
+    cell_colors: Vec<Lab>,
+    // See CollageStats
+    quality_score: Option<f32>,
+    // --palette-quantize palette, every pixel of image is one of its entries
+    palette: Option<Vec<Rgba<u8>>>,
+}
+
+/// What a `Recreate` is doing, passed to the handler of `Recreate::set_event_handler`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecreateEvent {
+    /// Images were added to the pool, which now holds this many.
+    PoolLoaded(usize),
+    /// Cell `idx` of the `total` grid cells got the pool image at `source_img_idx`.
+    /// Cells that keep the reference pixels have no event, and tiles are completed in any order.
+    TileCompleted {
+        idx: u32,
+        total: u32,
+        source_img_idx: usize,
+    },
+    /// A phase of building a collage started: "grid", "assign", "render" or "save".
+    PhaseStarted(&'static str),
+    /// A phase of building a collage finished after the given time.
+    PhaseCompleted(&'static str, Duration),
+    /// The collage was saved at this path.
+    CollageSaved(PathBuf),
+    /// Loading images or building a collage failed with this message, the error is returned as well.
+    Error(String),
+}
+
+type EventHandler = dyn Fn(RecreateEvent) + Send + Sync;
+
+/// Pool of tile images that collages are built from.
+/// It can be shared between threads, e.g. as an `Arc<Recreate>`: collages are built concurrently, while loading
+/// images into the pool waits for the collages in progress.
+#[derive(Default)]
+pub struct Recreate {
+    pool: RwLock<Pool>,
+    event_handler: Option<Box<EventHandler>>,
+}
+
+impl fmt::Debug for Recreate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recreate")
+            .field("pool", &self.pool)
+            .field("event_handler", &self.event_handler.is_some())
+            .finish()
+    }
+}
+
+/// The images of a `Recreate` and the per image data computed from them.
+#[derive(Debug, Default)]
+struct Pool {
+    img_list: Vec<DynamicImage>,
+    // File name of each image in img_list, in the same order
+    img_names: Vec<String>,
+    // Dominant color of each image in img_list, in the same order
+    pool_colors: Vec<Lab>,
+    // Options pool_colors was computed with, a collage with other ones computes them again
+    pool_colors_settings: Option<ColorSettings>,
+    // Mean Lab lightness (L*) of each image in img_list, in the same order
+    pool_lightness: Vec<f32>,
+    // Lab histogram of each image in img_list, in the same order, only filled for --distance-metric histogram metrics
+    pool_histograms: Vec<LabHistogram>,
+    // --nn-model, with the path it was loaded from, and the embedding of each image in img_list by it, in the same order,
+    // only loaded for --distance-metric nn
+    #[cfg(feature = "nn-match")]
+    nn_model: Option<(String, nn::FeatureExtractor)>,
+    #[cfg(feature = "nn-match")]
+    pool_embeddings: Vec<Vec<f32>>,
+    // Indices into img_list of landscape and portrait images, only filled when splitting by aspect ratio.
+    // Square images belong to both.
+    landscape_pool: Vec<usize>,
+    portrait_pool: Vec<usize>,
+    // Indices into img_list of the images loaded from --mix-pool, in ascending order
+    mix_pool: Vec<usize>,
+}
+
+/// Lab color segment of the pool, used to narrow down best-match lookups.
+/// Segments depend on the cell's tile candidates, so they are computed for every collage rather than kept in the pool.
+#[derive(Debug)]
+struct PoolSegment {
+    centroid: Lab,
+    // Indices into img_list of the images belonging to this segment
+    members: Vec<usize>,
+}
+
+impl Recreate {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `handler` with every `RecreateEvent` from now on. It is called from the threads doing the work, while the
+    /// pool is in use, so it shouldn't call back into the `Recreate`.
+    pub fn set_event_handler(&mut self, handler: impl Fn(RecreateEvent) + Send + Sync + 'static) {
+        self.event_handler = Some(Box::new(handler));
+    }
+
+    fn emit(&self, event: RecreateEvent) {
+        if let Some(handler) = &self.event_handler {
+            handler(event);
+        }
+    }
+
+    /// Passes the error of `result`, if any, on to the event handler.
+    fn reported<T>(&self, result: Result<T>) -> Result<T> {
+        result.inspect_err(|e| self.emit(RecreateEvent::Error(format!("{:#}", e))))
+    }
+
+    /// Reports the outcome of adding images to the pool, `loaded` being the size of the pool after.
+    fn pool_loaded(&self, loaded: Result<usize>) -> Result<()> {
+        let len = self.reported(loaded)?;
+        self.emit(RecreateEvent::PoolLoaded(len));
+        Ok(())
+    }
+
+    /// The pool for reading, held by every collage in progress.
+    fn pool(&self) -> RwLockReadGuard<'_, Pool> {
+        self.pool.read().unwrap()
+    }
+
+    /// The pool for changing, waits for the collages in progress to finish.
+    fn pool_mut(&self) -> RwLockWriteGuard<'_, Pool> {
+        self.pool.write().unwrap()
+    }
+
+    /// Number of images in the pool.
+    pub fn pool_len(&self) -> usize {
+        self.pool().img_list.len()
+    }
+
+    /// Whether no images have been loaded into the pool.
+    pub fn pool_is_empty(&self) -> bool {
+        self.pool().img_list.is_empty()
+    }
+
+    /// Removes every image from the pool, along with everything computed from them.
+    pub fn clear_pool(&self) {
+        self.pool_mut().clear();
+    }
+
+    /// Replaces the pool with the images in the directory `dir`, loaded with the command line defaults.
+    /// Collages built meanwhile see either the old pool or the new one.
+    pub fn reload_pool(&self, dir: &str) -> Result<()> {
+        let args = Args::library_defaults(dir, "");
+        self.reload_pool_with_tone_mapper(dir, args.tonemapping.mapper())
+    }
+
+    /// Like `reload_pool`, but HDR images are tone mapped to 8 bit with `tone_mapper` instead of the default operator.
+    pub fn reload_pool_with_tone_mapper(
+        &self,
+        dir: &str,
+        tone_mapper: &dyn ToneMapper,
+    ) -> Result<()> {
+        let args = Args::library_defaults(dir, "");
+        let loaded = load_pool_dir_with_tone_mapper(dir, "", &args, tone_mapper).and_then(
+            |(images, names)| {
+                let mut pool = self.pool_mut();
+                pool.clear();
+                pool.add_images(images, names, &args);
+                match pool.img_list.len() {
+                    0 => Err(anyhow!("No pool images could be loaded from {}", dir)),
+                    len => Ok(len),
+                }
+            },
+        );
+        self.pool_loaded(loaded)
+    }
+
+    /// Dominant color of every pool image, in pool order, or None until they have been computed for the current pool.
+    pub fn pool_dominant_colors(&self) -> Option<Vec<Lab>> {
+        let pool = self.pool();
+        (!pool.pool_colors.is_empty() && pool.pool_colors.len() == pool.img_list.len())
+            .then(|| pool.pool_colors.clone())
+    }
+
+    /// Adds the images in the directory `dir_path` to the pool, except `ref_img`.
+    /// The images are decoded before the pool is locked, so collages in progress only wait for them to be added.
+    fn read_dir_to_vec(&self, dir_path: &str, ref_img: &str, args: &Args) -> Result<()> {
+        let loaded = load_pool_dir(dir_path, ref_img, args).and_then(|(images, names)| {
+            let mut pool = self.pool_mut();
+            pool.add_images(images, names, args);
+            match pool.img_list.len() {
+                0 => Err(anyhow!("No pool images could be loaded from {}", dir_path)),
+                len => Ok(len),
+            }
+        });
+        self.pool_loaded(loaded)
+    }
+
+    /// Adds the encoded images in `images` to the pool, the in-memory counterpart of `read_dir_to_vec`.
+    /// Images are named by their position in `images`.
+    fn read_memory_to_vec<B: AsRef<[u8]>>(&self, images: &[B], args: &Args) -> Result<()> {
+        let decoded: Result<Vec<DynamicImage>> = images
+            .iter()
+            .enumerate()
+            .map(|(idx, bytes)| {
+                let mut img = image::load_from_memory(bytes.as_ref())
+                    .with_context(|| format!("Couldn't decode pool image {}", idx))?;
+
+                if tonemap::is_hdr(&img) {
+                    img = tonemap::tonemap(&img, args.tonemapping.mapper());
+                }
+
+                if args.strip_alpha {
+                    img = strip_alpha(img, args.alpha_fill);
+                }
+
+                Ok(img)
+            })
+            .collect();
+        let loaded = decoded.and_then(|img_list| {
+            let img_names = (0..img_list.len()).map(|idx| idx.to_string()).collect();
+            let mut pool = self.pool_mut();
+            pool.add_images(img_list, img_names, args);
+            match pool.img_list.len() {
+                0 => Err(anyhow!("No pool images were given")),
+                len => Ok(len),
+            }
+        });
+        self.pool_loaded(loaded)
+    }
+
+    /// Adds the image at `path` to the pool, prepared like the images of `read_dir_to_vec`.
+    fn add_pool_image(&self, path: &Path, args: &Args) -> Result<()> {
+        let alpha_fill = args.strip_alpha.then_some(args.alpha_fill);
+        let img = load_pool_image(
+            path,
+            args.tonemapping.mapper(),
+            alpha_fill,
+            args.respect_icc_profile,
+        )?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_owned();
+
+        self.pool_mut().add_images(vec![img], vec![name], args);
+        Ok(())
+    }
+
+    /// Adds the images listed in the manifest at `manifest_path` to the pool, see `manifest::resolve_manifest`.
+    fn read_manifest_to_vec(&self, manifest_path: &str, args: &Args) -> Result<()> {
+        let cache_dir = args
+            .manifest_cache_dir
+            .as_ref()
+            .map_or_else(|| args.temp_dir().join("recreate-manifest"), PathBuf::from);
+        print_if!(
+            args.verbose,
+            "Caching manifest downloads in {}",
+            cache_dir.display()
+        );
+        let paths = self.reported(manifest::resolve_manifest(
+            manifest_path,
+            &cache_dir,
+            args.manifest_user_agent.as_deref(),
+            args.keep_temp,
+        ))?;
+
+        let mut added = 0;
+        let mut errors = Vec::new();
+        for path in paths {
+            match self.add_pool_image(&path, args) {
+                Ok(()) => added += 1,
+                Err(e) if args.tolerate_errors => eprintln!("Skipped pool image: {:#}", e),
+                Err(e) => errors.push(e),
+            }
+        }
+        if !errors.is_empty() {
+            let failures: Vec<String> = errors.iter().map(|e| format!("{:#}", e)).collect();
+            return self.pool_loaded(Err(anyhow!(
+                "{}\nPass --tolerate-errors to build the collage from the pool images that did load",
+                failures.join("\n")
+            )));
+        }
+        print_if!(
+            args.verbose,
+            "Loaded {} images from manifest {}",
+            added,
+            manifest_path
+        );
+        self.pool_loaded(Ok(self.pool_len()))
+    }
+
+    /// Loads the --mix-pool directory into the pool after the main pool.
+    fn read_mix_pool(&self, dir_path: &str, ref_img: &str, args: &Args) -> Result<()> {
+        let loaded = load_pool_dir(dir_path, ref_img, args).and_then(|(images, names)| {
+            if images.is_empty() {
+                return Err(anyhow!("No pool images could be loaded from {}", dir_path));
+            }
+
+            let mut pool = self.pool_mut();
+            let start = pool.img_list.len();
+            pool.add_images(images, names, args);
+            pool.mix_pool = (start..pool.img_list.len()).collect();
+            print_if!(
+                args.verbose,
+                "Loaded {} mix pool images from {}",
+                pool.mix_pool.len(),
+                dir_path
+            );
+            Ok(pool.img_list.len())
+        });
+        self.pool_loaded(loaded)
+    }
+
+    /// Writes one CSV row of metadata per pool image, in pool order.
+    fn write_pool_stats_csv(&self, csv_path: &str, args: &Args) -> Result<()> {
+        self.pool_mut().write_pool_stats_csv(csv_path, args)
+    }
+
+    /// Puts the pool in a random order, see `Pool::shuffle`.
+    fn shuffle_pool(&self, args: &Args) {
+        self.pool_mut().shuffle(args);
+    }
+
+    /// Runs `build` on the pool once it has the per image data a collage with `args` needs, computing what's missing
+    /// first. Collages that find the data ready are built concurrently, computing it waits for them like loading does.
+    fn with_prepared_pool<T>(
+        &self,
+        args: &Args,
+        build: impl FnOnce(&Pool) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            let pool = self.pool();
+            if pool.is_prepared(args) {
+                return build(&pool);
+            }
+            drop(pool);
+            // the pool may change again before it is read, so this checks again
+            self.pool_mut().prepare(args)?;
+        }
+    }
+
+    fn collage(&self, args: &Args) -> Result<CollageStats> {
+        self.collage_named(args, "output")
+    }
+
+    /// `collage`, saving the collage next to the reference image as `file_stem` with the extension of each format
+    /// unless --output is given.
+    fn collage_named(&self, args: &Args, file_stem: &str) -> Result<CollageStats> {
+        self.reported(self.with_prepared_pool(args, |pool| {
+            pool.collage_named(args, file_stem, &|event| self.emit(event))
+        }))
+    }
+
+    /// Builds the collage of --ref like `collage` does, but returns it instead of saving it.
+    /// Debug images are still written next to the reference image.
+    fn collage_in_memory(&self, args: &Args) -> Result<DynamicImage> {
+        self.reported(self.with_prepared_pool(args, |pool| {
+            pool.build_ref_collage(args, &|event| self.emit(event))
+                .map(|collage| collage.image)
+        }))
+    }
+
+    /// Builds the collage of the reference image `img` and returns it encoded as PNG instead of saving it.
+    /// Debug images are written to the working directory.
+    fn collage_to_png(&self, img: DynamicImage, args: &Args) -> Result<Vec<u8>> {
+        self.reported(check_args(args).and_then(|()| {
+            let collage = self.with_prepared_pool(args, |pool| {
+                pool.build_collage(img, Path::new("."), args, &|event| self.emit(event))
+            })?;
+
+            let mut png = Vec::new();
+            match &collage.palette {
+                Some(palette) => {
+                    postprocess::write_indexed_png(&collage.image, palette, &mut png, None)?
+                }
+                None => OutputFormat::Png.write(&collage.image, &mut png, None)?,
+            }
+            Ok(png)
+        }))
+    }
+}
+
+impl Pool {
+    /// Removes every image, along with everything computed from them.
+    fn clear(&mut self) {
+        self.img_list.clear();
+        self.img_names.clear();
+        self.landscape_pool.clear();
+        self.portrait_pool.clear();
+        self.mix_pool.clear();
+        self.clear_caches();
+    }
+
+    /// Drops the per image data computed from the pool, which is computed again when next needed.
+    fn clear_caches(&mut self) {
+        self.pool_colors.clear();
+        self.pool_colors_settings = None;
+        self.pool_lightness.clear();
+        self.pool_histograms.clear();
+        #[cfg(feature = "nn-match")]
+        self.pool_embeddings.clear();
+    }
+
+    /// Appends `images`, named `names`, to the pool.
+    fn add_images(&mut self, images: Vec<DynamicImage>, names: Vec<String>, args: &Args) {
+        self.img_list.extend(images);
+        self.img_names.extend(names);
+        if args.split_by_aspect {
+            self.split_pool_by_aspect(args.verbose);
+        }
+    }
+
+    /// Whether the pool has the per image data a collage with `args` uses, see `prepare`.
+    fn is_prepared(&self, args: &Args) -> bool {
+        let len = self.img_list.len();
+        let has_colors = self.pool_colors.len() == len
+            && self.pool_colors_settings == Some(args.color_settings())
+            && (!args.matches_histograms() || self.pool_histograms.len() == len);
+        #[cfg(feature = "nn-match")]
+        if args.matches_embeddings()
+            && (self.nn_model.as_ref().map(|(path, _)| path.as_str()) != args.nn_model.as_deref()
+                || self.pool_embeddings.len() != len)
+        {
+            return false;
+        }
+        (!args.uses_dominant_colors() || has_colors)
+            && (!args.uses_mean_lightness() || self.pool_lightness.len() == len)
+    }
+
+    /// Computes the per image data a collage with `args` uses and the pool doesn't have yet.
+    /// This is the only part of building a collage that changes the pool.
+    fn prepare(&mut self, args: &Args) -> Result<()> {
+        if args.uses_dominant_colors() {
+            self.precompute_dominant_colors(args);
+        }
+        if args.uses_mean_lightness() {
+            self.precompute_mean_lightness(args.verbose);
+        }
+        #[cfg(feature = "nn-match")]
+        self.precompute_embeddings(args)?;
+        Ok(())
+    }
+
+    /// Computes the mean lightness (L*) of every image in the pool, cached like `precompute_dominant_colors`.
+    fn precompute_mean_lightness(&mut self, verbose: bool) {
+        let img_list = &self.img_list;
+        if self.pool_lightness.len() == img_list.len() {
+            return;
+        }
+
+        print_if!(
+            verbose,
+            "Computing mean lightness for {} pool images",
+            img_list.len()
+        );
+        let lightness = img_list
+            .par_iter()
+            .map(|img| {
+                let thumbnail = img.thumbnail(64, 64).to_rgb8();
+                let sum: f32 = from_component_slice::<Srgb<u8>>(thumbnail.as_raw())
+                    .iter()
+                    .map(|rgb| {
+                        let lab: Lab = rgb.into_format().into_color();
+                        lab.l
+                    })
+                    .sum();
+                sum / (thumbnail.width() * thumbnail.height()).max(1) as f32
+            })
+            .collect();
+        self.pool_lightness = lightness;
+    }
+
+    /// Writes one CSV row of metadata per pool image, in `img_list` order.
+    fn write_pool_stats_csv(&mut self, csv_path: &str, args: &Args) -> Result<()> {
+        let verbose = args.verbose;
+        self.precompute_dominant_colors(args);
+        let img_list = &self.img_list;
+        let img_names = &self.img_names;
+
+        // mean and RMS contrast of the Rec. 709 luma, both in the 0-1 range
+        let luminance_stats: Vec<(f32, f32)> = img_list
+            .par_iter()
+            .map(|img| {
+                let luma: Vec<f32> = img
+                    .to_rgb8()
+                    .pixels()
+                    .map(|p| {
+                        (0.2126 * p[0] as f32 + 0.7152 * p[1] as f32 + 0.0722 * p[2] as f32) / 255.0
+                    })
+                    .collect();
+                let count = luma.len().max(1) as f32;
+                let mean = luma.iter().sum::<f32>() / count;
+                let variance = luma.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / count;
+                (mean, variance.sqrt())
+            })
+            .collect();
+
+        let mut writer = csv::Writer::from_path(csv_path)
+            .with_context(|| format!("Couldn't create pool stats CSV in path: {}", csv_path))?;
+        writer.write_record([
+            "filename",
+            "width",
+            "height",
+            "aspect_ratio",
+            "dominant_lab_l",
+            "dominant_lab_a",
+            "dominant_lab_b",
+            "dominant_hex",
+            "mean_luminance",
+            "rms_contrast",
+        ])?;
+        for (idx, img) in img_list.iter().enumerate() {
+            let (width, height) = img.dimensions();
+            let lab = self.pool_colors[idx];
+            let (mean_luminance, rms_contrast) = luminance_stats[idx];
+            writer.write_record([
+                img_names[idx].clone(),
+                width.to_string(),
+                height.to_string(),
+                (width as f32 / height as f32).to_string(),
+                lab.l.to_string(),
+                lab.a.to_string(),
+                lab.b.to_string(),
+                rgba_to_hex(lab_to_rgba_u8(lab, None)),
+                mean_luminance.to_string(),
+                rms_contrast.to_string(),
+            ])?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Couldn't write pool stats CSV in path: {}", csv_path))?;
+
+        print_if!(verbose, "Pool stats written to -> {}", csv_path);
+        Ok(())
+    }
+
+    /// Splits the pool into landscape and portrait images so cells can be filled with images of a similar shape.
+    fn split_pool_by_aspect(&mut self, verbose: bool) {
+        let img_list = &self.img_list;
+        self.landscape_pool.clear();
+        self.portrait_pool.clear();
+
+        for (idx, img) in img_list.iter().enumerate() {
+            let (width, height) = img.dimensions();
+            if width >= height {
+                self.landscape_pool.push(idx);
+            }
+            if height >= width {
+                self.portrait_pool.push(idx);
+            }
+        }
+
+        print_if!(
+            verbose,
+            "Split pool into {} landscape and {} portrait images",
+            self.landscape_pool.len(),
+            self.portrait_pool.len()
+        );
+    }
+
+    /// Returns the indices of the pool images that may be placed in cells of the given size.
+    /// When splitting by aspect ratio this is the pool matching the cell's shape, otherwise the whole pool.
+    fn tile_candidates(&self, args: &Args, cell_width: u32, cell_height: u32) -> Vec<usize> {
+        if args.split_by_aspect {
+            let pool = if cell_width >= cell_height {
+                &self.landscape_pool
+            } else {
+                &self.portrait_pool
+            };
+            if !pool.is_empty() {
+                return pool.clone();
+            }
+            status!("warning: no pool images match the cell aspect ratio, using the whole pool");
+        }
+
+        (0..self.img_list.len()).collect()
+    }
+
+    /// Farthest-point sampling of `candidates` by dominant color: starting from the color farthest from the
+    /// pool's mean, each pick is the image whose color is farthest from every image picked so far.
+    fn equalize_colors(&self, candidates: &[usize], size: usize, verbose: bool) -> Vec<usize> {
+        if size >= candidates.len() {
+            return candidates.to_vec();
+        }
+        print_if!(
+            verbose,
+            "Equalizing pool colors, keeping {} of {} pool images",
+            size,
+            candidates.len()
+        );
+
+        let colors: Vec<Lab> = candidates
+            .iter()
+            .map(|&idx| self.pool_colors[idx])
+            .collect();
+        let mean = colors.iter().fold(Lab::new(0.0, 0.0, 0.0), |sum, &color| {
+            Lab::new(sum.l + color.l, sum.a + color.a, sum.b + color.b)
+        }) / colors.len() as f32;
+
+        let farthest = |distances: &[f32]| {
+            distances
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(idx, _)| idx)
+                .unwrap()
+        };
+
+        let mean_distances: Vec<f32> = colors.iter().map(|&color| color.distance(mean)).collect();
+        let mut next = farthest(&mean_distances);
+        // distance of every candidate to the closest picked color
+        let mut nearest = vec![f32::INFINITY; colors.len()];
+        let mut picked = Vec::with_capacity(size);
+        loop {
+            picked.push(candidates[next]);
+            if picked.len() == size {
+                return picked;
+            }
+            for (distance, &color) in nearest.iter_mut().zip(&colors) {
+                *distance = distance.min(color.distance(colors[next]));
+            }
+            // keeps picked images out even when another image has the exact same color
+            nearest[next] = f32::NEG_INFINITY;
+            next = farthest(&nearest);
+        }
+    }
+
+    /// Computes the dominant color of every image in the pool.
+    /// Results are cached so this only does work when the pool or the options the colors depend on
+    /// (--blend-target, --dominant-sample-size, --profile) have changed since the last call.
+    /// The Lab histograms of the pool images are computed alongside when --distance-metric needs them.
+    fn precompute_dominant_colors(&mut self, args: &Args) {
+        let verbose = args.verbose;
+        let img_list = &self.img_list;
+        let settings = args.color_settings();
+        if self.pool_colors.len() != img_list.len() || self.pool_colors_settings != Some(settings) {
+            print_if!(
+                verbose,
+                "Computing dominant colors for {} pool images",
+                img_list.len()
+            );
+            // thumbnails are plenty for a dominant color and keep k-means fast on large photos
+            self.pool_colors = img_list
+                .par_iter()
+                .map(|img| cell_color(img.thumbnail(64, 64).to_rgb8().into_raw(), args))
+                .collect();
+            self.pool_colors_settings = Some(settings);
+        }
+
+        if args.matches_histograms() && self.pool_histograms.len() != img_list.len() {
+            print_if!(
+                verbose,
+                "Computing Lab histograms for {} pool images",
+                img_list.len()
+            );
+            self.pool_histograms = img_list
+                .par_iter()
+                .map(|img| LabHistogram::new(img.thumbnail(64, 64).to_rgb8().as_raw()))
+                .collect();
+        }
+    }
+
+    /// Loads --nn-model and computes the embeddings of the pool images, both are kept until the pool or the model path changes.
+    #[cfg(feature = "nn-match")]
+    fn precompute_embeddings(&mut self, args: &Args) -> Result<()> {
+        if !args.matches_embeddings() {
+            return Ok(());
+        }
+        let model_path = args.nn_model.as_deref().expect("clap requires --nn-model");
+        let model = match &mut self.nn_model {
+            Some((path, model)) if path == model_path => model,
+            model => {
+                // embeddings of another model aren't comparable with the new one's
+                self.pool_embeddings.clear();
+                let loaded = nn::FeatureExtractor::new(model_path)?;
+                &model.insert((model_path.to_owned(), loaded)).1
+            }
+        };
+        let img_list = &self.img_list;
+        if self.pool_embeddings.len() != img_list.len() {
+            print_if!(
+                args.verbose,
+                "Computing embeddings for {} pool images",
+                img_list.len()
+            );
+            self.pool_embeddings = model.embed(img_list)?;
+        }
+        Ok(())
+    }
+
+    /// Features of the grid cells the selected --distance-metric compares.
+    fn cell_features(&self, image_grid: &[DynamicImage], args: &Args) -> Result<CellFeatures> {
+        let mut features = CellFeatures::default();
+        if args.matches_histograms() {
+            features.histograms = image_grid
+                .par_iter()
+                .map(|portion| LabHistogram::new(portion.to_rgb8().as_raw()))
+                .collect();
+        }
+        #[cfg(feature = "nn-match")]
+        if let (true, Some((_, model))) = (args.matches_embeddings(), &self.nn_model) {
+            features.embeddings = model.embed(image_grid)?;
+        }
+        Ok(features)
+    }
+
+    /// Partitions the `candidates` pool images into (at most) `clusters` Lab color segments by running k-means on their dominant colors.
+    /// Requires `precompute_dominant_colors` to have been called.
+    fn partition_pool(
+        &self,
+        clusters: u32,
+        candidates: &[usize],
+        verbose: bool,
+    ) -> Vec<PoolSegment> {
+        // kmeans_colors stores cluster indices as u8
+        let k = (clusters as usize)
+            .min(candidates.len())
+            .min(u8::MAX as usize);
+
+        if k <= 1 {
+            return vec![PoolSegment {
+                centroid: Lab::default(),
+                members: candidates.to_vec(),
+            }];
+        }
+
+        print_if!(verbose, "Partitioning image pool into {} color segments", k);
+        let colors: Vec<Lab> = candidates
+            .iter()
+            .map(|&img_idx| self.pool_colors[img_idx])
+            .collect();
+        let result = get_kmeans(k, 20, 5.0, false, &colors, 42);
+        let mut segments: Vec<PoolSegment> = result
+            .centroids
+            .iter()
+            .map(|&centroid| PoolSegment {
+                centroid,
+                members: Vec::new(),
+            })
+            .collect();
+        for (&img_idx, &segment) in candidates.iter().zip(&result.indices) {
+            segments[segment as usize].members.push(img_idx);
+        }
+        segments.retain(|segment| !segment.members.is_empty());
+        segments
+    }
+
+    /// Returns the index of the pool image whose `distance` to the cell is the smallest.
+    /// Only images in the one of `segments` closest to the cell's dominant `color` (plus `neighbour_clusters` next
+    /// closest segments) are considered.
+    /// Only images for which `allowed` returns true are considered, if the searched segments have none of them the
+    /// whole pool is searched.
+    fn best_match(
+        &self,
+        segments: &[PoolSegment],
+        color: Lab,
+        neighbour_clusters: u32,
+        allowed: impl Fn(usize) -> bool,
+        distance: impl Fn(usize) -> f32,
+    ) -> usize {
+        let mut segments: Vec<&PoolSegment> = segments.iter().collect();
+        segments.sort_by(|a, b| {
+            a.centroid
+                .distance_squared(color)
+                .total_cmp(&b.centroid.distance_squared(color))
+        });
+
+        let closest = |segments: &[&PoolSegment]| {
+            segments
+                .iter()
+                .flat_map(|segment| segment.members.iter().copied())
+                .filter(|&img_idx| allowed(img_idx))
+                .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+        };
+        let searched = (1 + neighbour_clusters as usize).min(segments.len());
+        closest(&segments[..searched])
+            .or_else(|| closest(&segments))
+            .unwrap_or(0)
+    }
+
+    /// Picks a pool image for every cell by Maximum Marginal Relevance (--mmr-lambda), where the similarity of two
+    /// colors is 1 - their Lab distance / 100. Each pick depends on the earlier ones, so this runs sequentially.
+    fn mmr_match(
+        &self,
+        segments: &[PoolSegment],
+        cell_colors: &[Lab],
+        neighbour_clusters: u32,
+        allowed: impl Fn(Lab, usize) -> bool,
+        lambda: f32,
+    ) -> Vec<usize> {
+        let similarity = |a: Lab, b: Lab| 1.0 - a.distance(b) / 100.0;
+        // highest similarity of each pool image to the tiles picked so far
+        let mut redundancy = vec![f32::NEG_INFINITY; self.pool_colors.len()];
+        let mut assignment = Vec::with_capacity(cell_colors.len());
+        for &color in cell_colors {
+            let img_idx = self.best_match(
+                segments,
+                color,
+                neighbour_clusters,
+                |img_idx| allowed(color, img_idx),
+                |img_idx| {
+                    // tiles unlike every pick, and all of them before the first pick, aren't penalized
+                    let redundancy = redundancy[img_idx].max(0.0);
+                    // best_match minimizes, so the negated MMR score
+                    (1.0 - lambda) * redundancy
+                        - lambda * similarity(self.pool_colors[img_idx], color)
+                },
+            );
+            let picked = self.pool_colors[img_idx];
+            for (max_sim, &tile) in redundancy.iter_mut().zip(&self.pool_colors) {
+                *max_sim = max_sim.max(similarity(tile, picked));
+            }
+            assignment.push(img_idx);
+        }
+        assignment
+    }
+
+    /// Puts `img_list` (and `img_names`) in a random order with a Fisher-Yates shuffle, keeping the index lists in sync.
+    fn shuffle(&mut self, args: &Args) {
+        let mut rng = args
+            .seed
+            .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        let mut order: Vec<usize> = (0..self.img_list.len()).collect();
+        order.shuffle(&mut rng);
+
+        let mut old_list: Vec<Option<DynamicImage>> = self.img_list.drain(..).map(Some).collect();
+        let mut old_names: Vec<Option<String>> = self.img_names.drain(..).map(Some).collect();
+        for &old_idx in &order {
+            self.img_list.extend(old_list[old_idx].take());
+            self.img_names.extend(old_names[old_idx].take());
+        }
+
+        // new_idx[old index] is the image's index after the shuffle
+        let mut new_idx = vec![0; order.len()];
+        for (idx, &old_idx) in order.iter().enumerate() {
+            new_idx[old_idx] = idx;
+        }
+        for pool in [
+            &mut self.mix_pool,
+            &mut self.landscape_pool,
+            &mut self.portrait_pool,
+        ] {
+            for img_idx in pool.iter_mut() {
+                *img_idx = new_idx[*img_idx];
+            }
+            pool.sort_unstable();
+        }
+        self.clear_caches();
+        print_if!(args.verbose, "Shuffled {} pool images", order.len());
+    }
+
+    /// Whether the image at `img_idx` in `img_list` was loaded from --mix-pool.
+    fn in_mix_pool(&self, img_idx: usize) -> bool {
+        self.mix_pool.binary_search(&img_idx).is_ok()
+    }
+
+    /// Picks a pool image index out of `candidates` for every grid cell based on the given strategy.
+    /// `cell_features` are only needed by the --distance-metric options besides centroid, and `segments`, the
+    /// `partition_pool` of the candidates, by best-match.
+    fn assign_tiles(
+        &self,
+        cell_colors: &[Lab],
+        cell_features: &CellFeatures,
+        candidates: &[usize],
+        segments: &[PoolSegment],
+        strategy: Strategy,
+        args: &Args,
+    ) -> Vec<usize> {
+        if args.sort_tiles_by_color {
+            self.assign_tiles_by_lightness(
+                cell_colors,
+                cell_features,
+                candidates,
+                segments,
+                strategy,
+                args,
+            )
+        } else {
+            self.assign_tiles_in_order(
+                cell_colors,
+                cell_features,
+                candidates,
+                segments,
+                strategy,
+                args,
+            )
+        }
+    }
+
+    /// `assign_tiles` in row-major cell order.
+    fn assign_tiles_in_order(
+        &self,
+        cell_colors: &[Lab],
+        cell_features: &CellFeatures,
+        candidates: &[usize],
+        segments: &[PoolSegment],
+        strategy: Strategy,
+        args: &Args,
+    ) -> Vec<usize> {
+        // with --mix-pool cells darker than the threshold pick from the mix pool, the rest from the main pool
+        let uses_mix_pool = |color: Lab| {
+            args.mix_pool
+                .as_ref()
+                .is_some_and(|mix| color.l < mix.threshold)
+        };
+        let (mix_candidates, main_candidates): (Vec<usize>, Vec<usize>) = candidates
+            .iter()
+            .partition(|&&img_idx| self.in_mix_pool(img_idx));
+        let group_candidates = |mix: bool| {
+            let group = if mix {
+                &mix_candidates
+            } else {
+                &main_candidates
+            };
+            // fall back to every candidate when a pool has nothing that fits the cell
+            if group.is_empty() {
+                candidates
+            } else {
+                group.as_slice()
+            }
+        };
+
+        match strategy {
+            Strategy::Random => {
+                let mut rng = args
+                    .seed
+                    .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+                cell_colors
+                    .iter()
+                    .map(|&color| {
+                        let candidates = group_candidates(uses_mix_pool(color));
+                        candidates[rng.gen_range(0..candidates.len())]
+                    })
+                    .collect()
+            }
+            Strategy::Shuffle => {
+                // each pool is cycled through separately
+                let mut next = [0, 0];
+                cell_colors
+                    .iter()
+                    .map(|&color| {
+                        let mix = uses_mix_pool(color);
+                        let candidates = group_candidates(mix);
+                        let img_idx = candidates[next[mix as usize] % candidates.len()];
+                        next[mix as usize] += 1;
+                        img_idx
+                    })
+                    .collect()
+            }
+            Strategy::BestMatch if args.mmr_lambda.is_some() => self.mmr_match(
+                segments,
+                cell_colors,
+                args.pool_neighbour_clusters,
+                |color, img_idx| {
+                    self.mix_pool.is_empty() || self.in_mix_pool(img_idx) == uses_mix_pool(color)
+                },
+                args.mmr_lambda.unwrap(),
+            ),
+            Strategy::BestMatch => cell_colors
+                .par_iter()
+                .enumerate()
+                .map(|(idx, &color)| {
+                    let mix = uses_mix_pool(color);
+                    let allowed =
+                        |img_idx| self.mix_pool.is_empty() || self.in_mix_pool(img_idx) == mix;
+                    let distance = |img_idx: usize| match args.distance_metric {
+                        DistanceMetric::Centroid => {
+                            self.pool_colors[img_idx].distance_squared(color)
+                        }
+                        DistanceMetric::HistogramIntersection => {
+                            1.0 - self.pool_histograms[img_idx]
+                                .intersection(&cell_features.histograms[idx])
+                        }
+                        DistanceMetric::Emd => self.pool_histograms[img_idx].emd(
+                            &cell_features.histograms[idx],
+                            args.emd_channels == EmdChannels::L,
+                        ),
+                        #[cfg(feature = "nn-match")]
+                        DistanceMetric::Nn => nn::cosine_distance(
+                            &cell_features.embeddings[idx],
+                            &self.pool_embeddings[img_idx],
+                        ),
+                    };
+                    self.best_match(
+                        segments,
+                        color,
+                        args.pool_neighbour_clusters,
+                        allowed,
+                        distance,
+                    )
+                })
+                .collect(),
+            Strategy::Anneal => self.parallel_tempering(cell_colors, candidates, args),
+            Strategy::LuminanceMatch => {
+                // candidates sorted by lightness so each cell is a binary search
+                let by_lightness = |candidates: &[usize]| {
+                    let mut sorted: Vec<(f32, usize)> = candidates
+                        .iter()
+                        .map(|&img_idx| (self.pool_lightness[img_idx], img_idx))
+                        .collect();
+                    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+                    sorted
+                };
+                let sorted = [
+                    by_lightness(group_candidates(false)),
+                    by_lightness(group_candidates(true)),
+                ];
+                cell_colors
+                    .par_iter()
+                    .map(|&color| {
+                        closest_lightness(&sorted[uses_mix_pool(color) as usize], color.l)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Runs --sa-chains simulated annealing chains in parallel, each at a multiple of the base temperature schedule,
+    /// and returns the assignment of the chain that ended with the lowest energy.
+    /// Every SA_EXCHANGE_INTERVAL steps neighbouring chains may swap configurations (replica exchange),
+    /// which lets good configurations found by the hot chains move down to the cold ones.
+    /// Needs `precompute_dominant_colors` to have run.
+    fn parallel_tempering(
+        &self,
+        cell_colors: &[Lab],
+        candidates: &[usize],
+        args: &Args,
+    ) -> Vec<usize> {
+        let base_seed = args.seed.unwrap_or_else(rand::random);
+        let chain_count = args.sa_chains.max(1);
+        let mut chains: Vec<AnnealChain> = (0..chain_count)
+            .map(|k| {
+                let mut rng = StdRng::seed_from_u64(base_seed.wrapping_add(k as u64));
+                let assignment: Vec<usize> = cell_colors
+                    .iter()
+                    .map(|_| candidates[rng.gen_range(0..candidates.len())])
+                    .collect();
+                let energy = self.assignment_energy(&assignment, cell_colors);
+                AnnealChain {
+                    assignment,
+                    energy,
+                    rng,
+                    temperature_scale: SA_CHAIN_LADDER.powi(k as i32),
+                }
+            })
+            .collect();
+        let mut exchange_rng = StdRng::seed_from_u64(base_seed.wrapping_add(chain_count as u64));
+
+        let mut step = 0;
+        while step < args.sa_iterations {
+            let steps = step..(step + SA_EXCHANGE_INTERVAL).min(args.sa_iterations);
+            chains
+                .par_iter_mut()
+                .for_each(|chain| self.anneal(chain, cell_colors, candidates, steps.clone(), args));
+            step = steps.end;
+
+            // replica exchange between chains at neighbouring temperatures
+            let temperature = sa_temperature(step, args);
+            for k in 1..chains.len() {
+                let (cold, hot) = (&chains[k - 1], &chains[k]);
+                let exponent = (cold.energy - hot.energy)
+                    * (1.0 / (temperature * cold.temperature_scale)
+                        - 1.0 / (temperature * hot.temperature_scale));
+                if exponent >= 0.0 || exchange_rng.gen::<f32>() < exponent.exp() {
+                    let (cold, hot) = chains.split_at_mut(k);
+                    let (cold, hot) = (&mut cold[k - 1], &mut hot[0]);
+                    std::mem::swap(&mut cold.assignment, &mut hot.assignment);
+                    std::mem::swap(&mut cold.energy, &mut hot.energy);
+                }
+            }
+        }
+
+        let best = chains
+            .into_iter()
+            .min_by(|a, b| a.energy.total_cmp(&b.energy))
+            .unwrap();
+        print_if!(
+            args.verbose,
+            "Simulated annealing ({} chains) finished with a total CIEDE2000 distance of {}",
+            chain_count,
+            best.energy
+        );
+        best.assignment
+    }
+
+    /// Total CIEDE2000 distance between each cell's dominant color and its tile's.
+    fn assignment_energy(&self, assignment: &[usize], cell_colors: &[Lab]) -> f32 {
+        assignment
+            .iter()
+            .enumerate()
+            .map(|(idx, &tile)| cell_colors[idx].difference(self.pool_colors[tile]))
+            .sum()
+    }
+
+    /// Runs the simulated annealing `steps` of the schedule on `chain`.
+    /// Every step proposes either swapping the tiles of two cells or replacing one cell's tile with a random candidate,
+    /// and accepts it with probability exp(-ΔE / T), see `sa_temperature` for T.
+    fn anneal(
+        &self,
+        chain: &mut AnnealChain,
+        cell_colors: &[Lab],
+        candidates: &[usize],
+        steps: Range<u64>,
+        args: &Args,
+    ) {
+        let cost = |cell: usize, tile: usize| cell_colors[cell].difference(self.pool_colors[tile]);
+        let AnnealChain {
+            assignment,
+            energy,
+            rng,
+            temperature_scale,
+        } = chain;
+        if assignment.is_empty() {
+            return;
+        }
+
+        for step in steps {
+            let temperature = sa_temperature(step, args) * *temperature_scale;
+            let a = rng.gen_range(0..assignment.len());
+            if rng.gen_bool(0.5) {
+                // swap the tiles of two cells
+                let b = rng.gen_range(0..assignment.len());
+                let (tile_a, tile_b) = (assignment[a], assignment[b]);
+                let delta = cost(a, tile_b) + cost(b, tile_a) - cost(a, tile_a) - cost(b, tile_b);
+                if accept(delta, temperature, rng) {
+                    assignment.swap(a, b);
+                    *energy += delta;
+                }
+            } else {
+                // replace the tile of one cell
+                let tile = candidates[rng.gen_range(0..candidates.len())];
+                let delta = cost(a, tile) - cost(a, assignment[a]);
+                if accept(delta, temperature, rng) {
+                    assignment[a] = tile;
+                    *energy += delta;
+                }
+            }
+        }
+    }
+
+    /// `assign_tiles` with --sort-tiles-by-color: cells are assigned in order of their L*, darkest first, and the picks
+    /// put back at the cells' grid positions. Shuffle gets the candidates sorted by lightness, which needs
+    /// `precompute_mean_lightness` to have run.
+    fn assign_tiles_by_lightness(
+        &self,
+        cell_colors: &[Lab],
+        cell_features: &CellFeatures,
+        candidates: &[usize],
+        segments: &[PoolSegment],
+        strategy: Strategy,
+        args: &Args,
+    ) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..cell_colors.len()).collect();
+        order.sort_by(|&a, &b| cell_colors[a].l.total_cmp(&cell_colors[b].l));
+        let sorted_colors: Vec<Lab> = order.iter().map(|&idx| cell_colors[idx]).collect();
+
+        let mut candidates = candidates.to_vec();
+        if strategy == Strategy::Shuffle {
+            candidates.sort_by(|&a, &b| self.pool_lightness[a].total_cmp(&self.pool_lightness[b]));
+        }
+        let sorted_assignment = self.assign_tiles_in_order(
+            &sorted_colors,
+            &cell_features.permuted(&order),
+            &candidates,
+            segments,
+            strategy,
+            args,
+        );
+
+        let mut assignment = vec![0; cell_colors.len()];
+        for (&idx, img_idx) in order.iter().zip(sorted_assignment) {
+            assignment[idx] = img_idx;
+        }
+        assignment
+    }
+
+    /// Pool image of every cell of a --from-grid-json tile grid, looked up by file name.
+    fn grid_assignment(
+        &self,
+        grid: &TileGrid,
+        is_tile_cell: impl Fn(usize) -> bool,
+    ) -> Result<Vec<usize>> {
+        let img_names = &self.img_names;
+        let mut by_name = HashMap::new();
+        for (img_idx, name) in img_names.iter().enumerate() {
+            by_name.entry(name.as_str()).or_insert(img_idx);
+        }
+
+        grid.cells
+            .iter()
+            .enumerate()
+            .map(|(idx, cell)| match cell {
+                Some(cell) => by_name.get(cell.source.as_str()).copied().ok_or_else(|| {
+                    anyhow!(
+                        "Pool image {} of the tile grid isn't in the pool",
+                        cell.source
+                    )
+                }),
+                None if is_tile_cell(idx) => Err(anyhow!(
+                    "Cell {},{} needs a tile but is null in the tile grid",
+                    idx as u32 / grid.cols,
+                    idx as u32 % grid.cols
+                )),
+                None => Ok(0),
+            })
+            .collect()
+    }
+
+    /// Hill climbing refinement of `assignment`: swaps the tiles of two neighbouring cells whenever that lowers
+    /// their combined Lab distance to the cells' dominant colors.
+    /// Runs up to `passes` sequential sweeps over the grid and stops early once a sweep makes no swaps.
+    /// Needs `precompute_dominant_colors` to have run.
+    fn refine_assignment(
+        &self,
+        assignment: &mut [usize],
+        cell_colors: &[Lab],
+        grid_cols: u32,
+        passes: u32,
+        is_tile_cell: impl Fn(usize) -> bool,
+        verbose: bool,
+    ) {
+        let grid_cols = grid_cols as usize;
+        let cost = |cell: usize, tile: usize| {
+            cell_colors[cell]
+                .distance_squared(self.pool_colors[tile])
+                .sqrt()
+        };
+
+        for pass in 0..passes {
+            let mut swaps = 0;
+            for idx in 0..assignment.len() {
+                if !is_tile_cell(idx) {
+                    continue;
+                }
+                // checking the right and bottom neighbour of every cell covers all 4 neighbours of a cell
+                let right = (idx % grid_cols + 1 < grid_cols).then_some(idx + 1);
+                let below = Some(idx + grid_cols).filter(|&below| below < assignment.len());
+                for neighbour in [right, below].into_iter().flatten() {
+                    if !is_tile_cell(neighbour) {
+                        continue;
+                    }
+                    let (a, b) = (assignment[idx], assignment[neighbour]);
+                    if cost(idx, b) + cost(neighbour, a) < cost(idx, a) + cost(neighbour, b) {
+                        assignment.swap(idx, neighbour);
+                        swaps += 1;
+                    }
+                }
+            }
+
+            print_if!(verbose, "Refinement pass {}: {} swaps", pass + 1, swaps);
+            if swaps == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Collages the `zoom` region of the reference at a finer grid density.
+    /// The region is upscaled by the zoom factor and divided into cells of the regular cell size,
+    /// then the result is scaled back down to the region's size so it can be placed over the regular collage.
+    #[allow(clippy::too_many_arguments)]
+    fn render_zoom(
+        &self,
+        ref_img: &DynamicImage,
+        zoom: Zoom,
+        cell_width: u32,
+        cell_height: u32,
+        candidates: &[usize],
+        segments: &[PoolSegment],
+        alpha_at: &(dyn Fn(u32, u32) -> f32 + Sync),
+        args: &Args,
+    ) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let (img_width, img_height) = ref_img.dimensions();
+        if zoom.width == 0
+            || zoom.height == 0
+            || zoom.x + zoom.width > img_width
+            || zoom.y + zoom.height > img_height
+        {
+            return Err(anyhow!(
+                "Zoom region {}x{} at ({}, {}) doesn't fit in the {}x{} reference image",
+                zoom.width,
+                zoom.height,
+                zoom.x,
+                zoom.y,
+                img_width,
+                img_height
+            ));
+        }
+
+        let zoom_cols = ((zoom.width as f32 * zoom.factor) as u32 / cell_width).max(1);
+        let zoom_rows = ((zoom.height as f32 * zoom.factor) as u32 / cell_height).max(1);
+        let mut region = ref_img
+            .crop_imm(zoom.x, zoom.y, zoom.width, zoom.height)
+            .resize_exact(
+                zoom_cols * cell_width,
+                zoom_rows * cell_height,
+                args.profile.filter(FilterType::CatmullRom),
+            );
+        let (region_width, region_height) = region.dimensions();
+        let region_grid = divide_image_into_grid(&mut region, zoom_cols, zoom_rows);
+
+        let region_colors: Vec<Lab> = region_grid
+            .par_iter()
+            .map(|portion| cell_color(portion.to_rgb8().into_raw(), args))
+            .collect();
+        let region_alphas: Vec<Option<u8>> = region_grid
+            .iter()
+            .map(|portion| args.lerp_alpha.then(|| mean_alpha(portion)))
+            .collect();
+        let region_features = self.cell_features(&region_grid, args)?;
+        let assignment = self.assign_tiles(
+            &region_colors,
+            &region_features,
+            candidates,
+            segments,
+            args.strategy,
+            args,
+        );
+
+        let region_buffer = RwLock::new(ImageBuffer::new(region_width, region_height));
+        let img_list = &self.img_list;
+        region_colors
+            .par_iter()
+            .enumerate()
+            .for_each(|(idx, &color)| {
+                let x_start = (idx as u32 % zoom_cols) * cell_width;
+                let y_start = (idx as u32 / zoom_cols) * cell_height;
+                let tile = blend_tile(
+                    &placed_tile(
+                        &img_list[assignment[idx]],
+                        args,
+                        idx as u32 % zoom_cols,
+                        idx as u32 / zoom_cols,
+                    ),
+                    cell_width,
+                    cell_height,
+                    args.profile.filter(FilterType::Lanczos3),
+                    blend_target(color, region_alphas[idx], args.blend_mode),
+                    // map back to collage coordinates for the alpha mask
+                    |x, y| {
+                        alpha_at(
+                            zoom.x + (x_start + x) * zoom.width / region_width,
+                            zoom.y + (y_start + y) * zoom.height / region_height,
+                        )
+                    },
+                    args.saturation,
+                    args.lerp_alpha,
+                    args.cell_padding,
+                    args.cell_quantize,
+                    args.dither,
+                    args.tile_match()
+                        .map(|tile_match| (tile_match, &region_grid[idx])),
+                );
+                image::imageops::replace(
+                    &mut *region_buffer.write().unwrap(),
+                    &tile,
+                    x_start as i64,
+                    y_start as i64,
+                );
+            });
+
+        Ok(image::imageops::resize(
+            &region_buffer.into_inner().unwrap(),
+            zoom.width,
+            zoom.height,
+            args.profile.filter(FilterType::Lanczos3),
+        ))
+    }
+
+    /// `collage`, saving the collage next to the reference image as `file_stem` with the extension of each format
+    /// unless --output is given.
+    fn collage_named(
+        &self,
+        args: &Args,
+        file_stem: &str,
+        emit: &(dyn Fn(RecreateEvent) + Sync),
+    ) -> Result<CollageStats> {
+        let path = args.r#ref.as_str();
+        let verbose = args.verbose;
+
+        let dir = output_dir(path);
+
+        let collage = self.build_ref_collage(args, emit)?;
+        let output_img = collage.image;
+
+        emit(RecreateEvent::PhaseStarted("save"));
+        let phase_start = Instant::now();
+
+        let icc_profile = match (&args.icc_profile, args.icc_preset) {
+            (Some(profile_path), _) => Some(fs::read(profile_path).with_context(|| {
+                format!(
+                    "Couldn't read ICC profile in specified path: {}",
+                    profile_path
+                )
+            })?),
+            (None, Some(preset)) => Some(preset.profile().to_vec()),
+            (None, None) => None,
+        };
+
+        // a --palette-quantize collage is saved as a palette-indexed png
+        let write = |format: OutputFormat, writer: &mut dyn Write| match &collage.palette {
+            Some(palette) if format == OutputFormat::Png => {
+                postprocess::write_indexed_png(&output_img, palette, writer, icc_profile.as_deref())
+            }
+            _ => format.write(&output_img, writer, icc_profile.as_deref()),
+        };
+
+        // Save the output image in every requested format
+        let mut output_paths = Vec::new();
+        for &format in &args.output_formats {
+            let output_path = args.output.clone().unwrap_or_else(|| {
+                let file_name = format!("{}.{}", file_stem, format.extension());
+                dir.join(file_name).to_string_lossy().into_owned()
+            });
+            if output_path == "-" {
+                let mut stdout = BufWriter::new(io::stdout().lock());
+                write(format, &mut stdout)
+                    .and_then(|()| Ok(stdout.flush()?))
+                    .context("Couldn't write image to standard output")?;
+                output_paths.push(output_path);
+                continue;
+            }
+            fs::File::create(&output_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| {
+                    let mut file = BufWriter::new(file);
+                    write(format, &mut file).and_then(|()| Ok(file.flush()?))
+                })
+                .with_context(|| format!("Couldn't save image in path: {}", output_path))?;
+
+            print_if!(
+                verbose,
+                "Image collage fully constructed. Check output at -> {}",
+                output_path
+            );
+            emit(RecreateEvent::CollageSaved(PathBuf::from(&output_path)));
+            output_paths.push(output_path);
+        }
+        emit(RecreateEvent::PhaseCompleted("save", phase_start.elapsed()));
+
+        Ok(CollageStats {
+            ref_path: path.to_owned(),
+            output_path: output_paths.remove(0),
+            width: output_img.width(),
+            height: output_img.height(),
+            grid_cols: collage.grid_cols,
+            grid_rows: collage.grid_rows,
+            pool_size: self.img_list.len(),
+            strategy: args.strategy,
+            alpha: args.alpha,
+            kmeans: args.profile.kmeans(),
+            quality_score: collage.quality_score,
+            cell_colors: collage
+                .cell_colors
+                .iter()
+                .map(|&lab| lab_to_rgba_u8(lab, None))
+                .collect(),
+        })
+    }
+
+    /// Opens --ref and builds its collage, the part of `collage` and `collage_in_memory` before saving.
+    fn build_ref_collage(
+        &self,
+        args: &Args,
+        emit: &(dyn Fn(RecreateEvent) + Sync),
+    ) -> Result<Collage> {
+        check_args(args)?;
+
+        status!("initiating collage process...");
+        let img = open_ref(args)?;
+
+        self.build_collage(img, output_dir(&args.r#ref), args, emit)
+    }
+
+    /// Builds the collage of the reference image `img` out of the loaded pool, passing its progress to `emit`.
+    /// Debug images are written to `dir`.
+    fn build_collage(
+        &self,
+        mut img: DynamicImage,
+        dir: &Path,
+        args: &Args,
+        emit: &(dyn Fn(RecreateEvent) + Sync),
+    ) -> Result<Collage> {
+        // every strategy indexes into the pool from worker threads, where an empty pool would panic
+        if self.img_list.is_empty() {
+            return Err(anyhow!("The pool has no images to build the collage from"));
+        }
+
+        let verbose = args.verbose;
+        let alpha = args.alpha;
+        let saturation = args.saturation;
+        let high_bit_depth = args.output_bit_depth == OutputBitDepth::Sixteen;
+
+        let (mut img_width, mut img_height) = img.dimensions();
+        print_if!(
+            verbose,
+            "ref_img_width: {}, ref_img_height: {}",
+            img_width,
+            img_height
+        );
+
+        if args.rotate_reference % 360.0 != 0.0 {
+            print_if!(
+                verbose,
+                "Rotating ref image by {} degrees",
+                args.rotate_reference
+            );
+            img = rotate_image(&img, args.rotate_reference, args.rotate_fill_color);
+            (img_width, img_height) = img.dimensions()
+        }
+
+        if args.resize {
+            print_if!(verbose, "Resizing ref image to {}x{}", img_width, img_width);
+            img = img.resize_exact(
+                img_width,
+                img_width,
+                args.profile.filter(FilterType::CatmullRom),
+            );
+            (img_width, img_height) = img.dimensions()
+        }
+
+        if args.scale != 0.0 {
+            let new_width = (img_width as f32 * args.scale).ceil() as u32;
+            let new_height = (img_height as f32 * args.scale).ceil() as u32;
+            print_if!(verbose, "Scaling ref image to {}x{}", new_width, new_height);
+            img = img.resize_exact(
+                new_width,
+                new_height,
+                args.profile.filter(FilterType::CatmullRom),
+            );
+            (img_width, img_height) = img.dimensions()
+        }
+
+        if args.ref_contrast > 0.0 {
+            print_if!(
+                verbose,
+                "Enhancing ref image local contrast by {} (sigma {})",
+                args.ref_contrast,
+                args.ref_contrast_sigma
+            );
+            img = enhance_local_contrast(&img, args.ref_contrast, args.ref_contrast_sigma);
+        }
+
+        let tile_grid = match &args.from_grid_json {
+            Some(grid_path) => {
+                print_if!(verbose, "Re-rendering the tile grid of {}", grid_path);
+                Some(TileGrid::read(grid_path)?)
+            }
+            None => None,
+        };
+        let (mut grid_cols, mut grid_rows) = match &tile_grid {
+            Some(grid) => {
+                if !img_width.is_multiple_of(grid.cols) || !img_height.is_multiple_of(grid.rows) {
+                    return Err(anyhow!(
+                        "The {}x{} tile grid doesn't divide the {}x{} ref image",
+                        grid.cols,
+                        grid.rows,
+                        img_width,
+                        img_height
+                    ));
+                }
+                (grid.cols, grid.rows)
+            }
+            None => {
+                let (cols, rows) = if args.auto_grid {
+                    let (cols, rows) =
+                        auto_grid(self.img_list.len(), img_width, img_height, args.max_reuse);
+                    print_if!(
+                        verbose,
+                        "Computed a {}x{} grid for {} pool images used {} times each",
+                        cols,
+                        rows,
+                        self.img_list.len(),
+                        args.max_reuse
+                    );
+                    (cols, rows)
+                } else {
+                    (args.cols, args.rows)
+                };
+                print_if!(
+                    verbose,
+                    "Attempting to adjust specified grid columns and rows"
+                );
+                (
+                    next_divisor(img_width, cols)?,
+                    next_divisor(img_height, rows)?,
+                )
+            }
+        };
+        print_if!(
+            verbose,
+            "Selected grid values-> grid_cols: {}, grid_rows: {}",
+            grid_cols,
+            grid_rows
+        );
+
+        let mut candidates =
+            self.tile_candidates(args, img_width / grid_cols, img_height / grid_rows);
+        if args.equalize_pool_colors {
+            let size = args
+                .equalize_pool_size
+                .map_or(candidates.len().div_ceil(2), |size| size as usize);
+            candidates = self.equalize_colors(&candidates, size, verbose);
+        }
+
+        let reuse_budget = candidates.len() as u64 * args.max_reuse as u64;
+        let grid_cells = grid_cols as u64 * grid_rows as u64;
+        if args.ref_scale_to_fit_pool && grid_cells > reuse_budget {
+            // keep the cell size and shrink the reference so fewer cells fit
+            let factor = (reuse_budget as f64 / grid_cells as f64).sqrt();
+            let new_cols = ((grid_cols as f64 * factor).floor() as u32).max(1);
+            let new_rows = ((grid_rows as f64 * factor).floor() as u32).max(1);
+            let new_width = new_cols * (img_width / grid_cols);
+            let new_height = new_rows * (img_height / grid_rows);
+            status!(
+                "warning: a {}x{} grid would use each of the {} pool images about {} times, scaling the ref image down to {}x{} ({}x{} grid) to stay within --max-reuse {}",
+                grid_cols,
+                grid_rows,
+                candidates.len(),
+                grid_cells.div_ceil(candidates.len().max(1) as u64),
+                new_width,
+                new_height,
+                new_cols,
+                new_rows,
+                args.max_reuse
+            );
+            img = img.resize_exact(
+                new_width,
+                new_height,
+                args.profile.filter(FilterType::CatmullRom),
+            );
+            (img_width, img_height) = img.dimensions();
+            (grid_cols, grid_rows) = (new_cols, new_rows);
+        }
+
+        if args.ref_pixelate > 1 {
+            print_if!(
+                verbose,
+                "Pixelating ref image in {}x{} blocks",
+                args.ref_pixelate,
+                args.ref_pixelate
+            );
+            img = pixelate(&img, args.ref_pixelate);
+        }
+
+        emit(RecreateEvent::PhaseStarted("grid"));
+        let phase_start = Instant::now();
+        print_if!(
+            verbose,
+            "Dividing reference image into {}x{} grid",
+            grid_cols,
+            grid_rows
+        );
+        let image_grid = divide_image_into_grid(&mut img, grid_cols, grid_rows);
+        print_if!(verbose, "Griding process complete");
+
+        // when mirroring only the cells in the source half/quadrant are built
+        let is_built_cell = |idx: usize| match args.mirror {
+            Some(mirror) => mirror.is_source_cell(
+                idx as u32 % grid_cols,
+                idx as u32 / grid_cols,
+                grid_cols,
+                grid_rows,
+            ),
+            None => true,
+        };
+
+        // with --ref-edges-only only cells containing an edge get a tile, the rest keep the reference pixels
+        let edge_cells = if args.ref_edges_only {
+            let edge_cells = detect_edge_cells(&img, grid_cols, grid_rows, args.edge_threshold);
+            print_if!(
+                verbose,
+                "{} of {} cells contain an edge",
+                edge_cells.iter().filter(|&&edge| edge).count(),
+                edge_cells.len()
+            );
+            edge_cells
+        } else {
+            vec![true; image_grid.len()]
+        };
+        let is_tile_cell = |idx: usize| is_built_cell(idx) && edge_cells[idx];
+
+        if args.min_unique_tiles > 0 {
+            // mirrored and edgeless cells don't show a tile of their own
+            let pool_len = candidates.len();
+            let tile_cells = (0..image_grid.len())
+                .filter(|&idx| is_tile_cell(idx))
+                .count();
+            let available = pool_len.min(tile_cells);
+            if available < args.min_unique_tiles as usize {
+                return Err(anyhow!(
+                    "--min-unique-tiles requires {} distinct images but only {} can be placed ({} pool images, {} tile cells), {} short",
+                    args.min_unique_tiles,
+                    available,
+                    pool_len,
+                    tile_cells,
+                    args.min_unique_tiles as usize - available
+                ));
+            }
+        }
+
+        // get dominant color of each grid portion
+        let cell_colors: Vec<Lab> = image_grid
+            .par_iter()
+            .enumerate()
+            .map(|(idx, portion)| {
+                if !is_built_cell(idx) {
+                    return Lab::default();
+                }
+                // a --from-grid-json grid already has the colors, cells without a tile don't need one
+                if let Some(grid) = &tile_grid {
+                    return grid.cells[idx].as_ref().map_or(Lab::default(), |cell| {
+                        let Rgba([r, g, b, _]) = cell.dominant;
+                        Srgb::new(r, g, b).into_format::<f32>().into_color()
+                    });
+                }
+                cell_color(portion.to_rgb8().into_raw(), args)
+            })
+            .collect();
+        // the alpha each cell's tile is blended towards with --lerp-alpha
+        let cell_alphas: Vec<Option<u8>> = image_grid
+            .iter()
+            .map(|portion| args.lerp_alpha.then(|| mean_alpha(portion)))
+            .collect();
+        emit(RecreateEvent::PhaseCompleted("grid", phase_start.elapsed()));
+
+        if args.ref_grid_overlay {
+            let overlay = debug::draw_ref_grid_overlay(
+                &img.to_rgba8(),
+                img_width / grid_cols,
+                img_height / grid_rows,
+                grid_cols,
+                &cell_colors
+                    .iter()
+                    .map(|&lab| lab_to_rgba_u8(lab, None))
+                    .collect::<Vec<_>>(),
+            );
+            let overlay_path = dir.join("ref_grid.png");
+            overlay.save(&overlay_path).with_context(|| {
+                format!("Couldn't save image in path: {}", overlay_path.display())
+            })?;
+            print_if!(
+                verbose,
+                "Ref grid overlay written to -> {}",
+                overlay_path.display()
+            );
+        }
+
+        emit(RecreateEvent::PhaseStarted("assign"));
+        let phase_start = Instant::now();
+        // the per image data of the pool comes from `Pool::prepare`
+        let segments = if tile_grid.is_none() && args.strategy == Strategy::BestMatch {
+            self.partition_pool(args.pool_clusters, &candidates, verbose)
+        } else {
+            Vec::new()
+        };
+        let mut assignment = match &tile_grid {
+            Some(grid) => self.grid_assignment(grid, is_tile_cell)?,
+            None => {
+                let features = self.cell_features(&image_grid, args)?;
+                self.assign_tiles(
+                    &cell_colors,
+                    &features,
+                    &candidates,
+                    &segments,
+                    args.strategy,
+                    args,
+                )
+            }
+        };
+
+        if args.refine_passes > 0 {
+            self.refine_assignment(
+                &mut assignment,
+                &cell_colors,
+                grid_cols,
+                args.refine_passes,
+                is_tile_cell,
+                verbose,
+            );
+        }
+
+        // the cell each cell copies its tile from, cells are their own source unless deduplicated
+        let mut cell_sources: Vec<usize> = (0..image_grid.len()).collect();
+        if args.cell_dedup_threshold > 0.0 {
+            cell_sources = dedup_cells(
+                &cell_colors,
+                &cell_alphas,
+                args.cell_dedup_threshold,
+                is_tile_cell,
+            );
+            for idx in 0..assignment.len() {
+                assignment[idx] = assignment[cell_sources[idx]];
+            }
+            print_if!(
+                verbose,
+                "{} cells reuse the tile of a near-duplicate cell",
+                cell_sources
+                    .iter()
+                    .enumerate()
+                    .filter(|&(idx, &source)| idx != source)
+                    .count()
+            );
+        }
+
+        if args.min_unique_tiles > 0 {
+            // only the cells showing a tile count, not the mirrored or edgeless ones
+            let tile_cells: Vec<usize> = (0..assignment.len())
+                .filter(|&idx| is_tile_cell(idx))
+                .collect();
+            let unique_tiles = count_unique(tile_cells.iter().map(|&idx| assignment[idx]));
+            if unique_tiles < args.min_unique_tiles as usize {
+                status!(
+                    "warning: {} strategy only used {} distinct images but --min-unique-tiles requires {}, switching to shuffle strategy",
+                    args.strategy, unique_tiles, args.min_unique_tiles
+                );
+                // shuffled over the tile cells alone, so the pool isn't spent on cells that don't show it
+                let tile_colors: Vec<Lab> =
+                    tile_cells.iter().map(|&idx| cell_colors[idx]).collect();
+                let shuffled = self.assign_tiles(
+                    &tile_colors,
+                    &CellFeatures::default(),
+                    &candidates,
+                    &[],
+                    Strategy::Shuffle,
+                    args,
+                );
+                for (&idx, img_idx) in tile_cells.iter().zip(shuffled) {
+                    assignment[idx] = img_idx;
+                }
+                cell_sources = (0..image_grid.len()).collect();
+
+                // --mix-pool can still leave too few images for the cells of one pool
+                let unique_tiles = count_unique(tile_cells.iter().map(|&idx| assignment[idx]));
+                if unique_tiles < args.min_unique_tiles as usize {
+                    return Err(anyhow!(
+                        "--min-unique-tiles requires {} distinct images but the shuffle strategy only placed {}, {} short",
+                        args.min_unique_tiles,
+                        unique_tiles,
+                        args.min_unique_tiles as usize - unique_tiles
+                    ));
+                }
+            }
+        }
+        emit(RecreateEvent::PhaseCompleted(
+            "assign",
+            phase_start.elapsed(),
+        ));
+
+        // re-rendering a --from-grid-json grid skips the pool's dominant colors the score needs,
+        // other runs only score when the colors are computed anyway, or --stats-json asked for them
+        let quality_score = if tile_grid.is_none() && args.uses_dominant_colors() {
+            let score: f32 = (0..assignment.len())
+                .filter(|&idx| is_tile_cell(idx))
+                .map(|idx| cell_colors[idx].difference(self.pool_colors[assignment[idx]]))
+                .sum();
+            print_if!(
+                verbose,
+                "Collage quality score: {:.1} (lower is better)",
+                score
+            );
+            Some(score)
+        } else {
+            None
+        };
+
+        // colors the tiles are blended towards, the cells' dominant colors unless graded with --target-histogram
+        let blend_colors = match &args.target_histogram {
+            Some(palette_path) => {
+                let palette_img = open(palette_path).with_context(|| {
+                    format!(
+                        "Couldn't open palette image in specified path: {}",
+                        palette_path
+                    )
+                })?;
+                let palette = kmeans_palette(
+                    palette_img.thumbnail(256, 256).to_rgb8().into_raw(),
+                    KmeansParams {
+                        clusters: TARGET_PALETTE_CLUSTERS,
+                        ..args.profile.kmeans()
+                    },
+                );
+                print_if!(
+                    verbose,
+                    "Matching cell colors to the {} color histogram of {}",
+                    palette.len(),
+                    palette_path
+                );
+                match_histogram(&cell_colors, &palette, is_tile_cell)
+            }
+            None => cell_colors.clone(),
+        };
+
+        // Create a shared buffer for the reconstructed image using Mutex for safe access
+        // only the buffer for the requested bit depth is allocated, the other stays empty
+        let (buffer8_size, buffer16_size) = if high_bit_depth {
+            ((0, 0), (img_width, img_height))
+        } else {
+            ((img_width, img_height), (0, 0))
+        };
+        let reconstructed_img_buffer = RwLock::new(ImageBuffer::<image::Rgba<u8>, Vec<u8>>::new(
+            buffer8_size.0,
+            buffer8_size.1,
+        ));
+        let reconstructed_img_buffer16 = RwLock::new(ImageBuffer::<Rgba<u16>, Vec<u16>>::new(
+            buffer16_size.0,
+            buffer16_size.1,
+        ));
+
+        // per-pixel alpha values, resized to the collage dimensions
+        let alpha_mask = match &args.alpha_mask {
+            Some(mask_path) => {
+                print_if!(verbose, "Loading alpha mask from {}", mask_path);
+                let mask = open(mask_path).with_context(|| {
+                    format!("Couldn't open alpha mask in specified path: {}", mask_path)
+                })?;
+                Some(
+                    mask.resize_exact(img_width, img_height, FilterType::Nearest)
+                        .to_luma8(),
+                )
+            }
+            None => None,
+        };
+
+        // --alpha eased by the distance of each cell's centre from the image centre, 0 there and 1 in the corners
+        let (cell_width, cell_height) = (img_width / grid_cols, img_height / grid_rows);
+        let tile_alphas: Option<Vec<f32>> = args.tile_blend_curve.map(|curve| {
+            (0..grid_cols * grid_rows)
+                .map(|idx| {
+                    let dx = ((idx % grid_cols) as f32 + 0.5) / grid_cols as f32 * 2.0 - 1.0;
+                    let dy = ((idx / grid_cols) as f32 + 0.5) / grid_rows as f32 * 2.0 - 1.0;
+                    let distance = ((dx * dx + dy * dy) / 2.0).sqrt();
+                    alpha * curve.ease(distance, args.tile_blend_bezier)
+                })
+                .collect()
+        });
+
+        let alpha_at = |x: u32, y: u32| match (&alpha_mask, &tile_alphas) {
+            (Some(mask), _) => mask.get_pixel(x, y)[0] as f32 / 255.0,
+            (None, Some(tile_alphas)) => {
+                let col = (x / cell_width).min(grid_cols - 1);
+                let row = (y / cell_height).min(grid_rows - 1);
+                tile_alphas[(row * grid_cols + col) as usize]
+            }
+            (None, None) => alpha,
+        };
+        if let Some(grid_path) = &args.output_grid_json {
+            let img_names = &self.img_names;
+            let grid = TileGrid {
+                cols: grid_cols,
+                rows: grid_rows,
+                cells: (0..image_grid.len())
+                    .map(|idx| {
+                        let col = idx as u32 % grid_cols;
+                        let row = idx as u32 / grid_cols;
+                        is_tile_cell(idx).then(|| GridCell {
+                            source: img_names[assignment[idx]].clone(),
+                            dominant: lab_to_rgba_u8(cell_colors[idx], None),
+                            blend_alpha: alpha_at(
+                                col * cell_width + cell_width / 2,
+                                row * cell_height + cell_height / 2,
+                            ),
+                        })
+                    })
+                    .collect(),
+            };
+            grid.write(grid_path)?;
+            print_if!(verbose, "Tile grid written to -> {}", grid_path);
+        }
+
+        // with an alpha mask or curve every tile is blended differently and with --tile-mirror-at-edges it may be
+        // flipped differently, so duplicates still need to be rendered
+        let copies_tile = |idx: usize| {
+            alpha_mask.is_none()
+                && tile_alphas.is_none()
+                && !args.tile_mirror_at_edges
+                && cell_sources[idx] != idx
+        };
+
+        print_if!(verbose, "Image collaging process initialized");
+        emit(RecreateEvent::PhaseStarted("render"));
+        let phase_start = Instant::now();
+        let total_cells = image_grid.len() as u32;
+        let tile_completed = |idx: usize| {
+            emit(RecreateEvent::TileCompleted {
+                idx: idx as u32,
+                total: total_cells,
+                source_img_idx: assignment[idx],
+            })
+        };
+        let img_list = &self.img_list;
+        // Parallel processing of image grid portions
+        image_grid
+            .par_iter()
+            .enumerate()
+            .for_each(|(idx, portion)| {
+                if !is_built_cell(idx) || copies_tile(idx) {
+                    return;
+                }
+
+                let (p_width, p_height) = portion.dimensions();
+                let dom_color = blend_target(blend_colors[idx], cell_alphas[idx], args.blend_mode);
+
+                let grid_x = idx as u32 % grid_cols;
+                let grid_y = idx as u32 / grid_cols;
+                let x_start = grid_x * p_width;
+                let y_start = grid_y * p_height;
+
+                if !edge_cells[idx] {
+                    if high_bit_depth {
+                        image::imageops::replace(
+                            &mut *reconstructed_img_buffer16.write().unwrap(),
+                            &portion.to_rgba16(),
+                            x_start as i64,
+                            y_start as i64,
+                        );
+                    } else {
+                        image::imageops::replace(
+                            &mut *reconstructed_img_buffer.write().unwrap(),
+                            &portion.to_rgba8(),
+                            x_start as i64,
+                            y_start as i64,
+                        );
+                    }
+                    return;
+                }
+
+                let tile_alpha_at = |x, y| alpha_at(x_start + x, y_start + y);
+                if high_bit_depth {
+                    let tile = blend_tile_16(
+                        &placed_tile(&img_list[assignment[idx]], args, grid_x, grid_y),
+                        p_width,
+                        p_height,
+                        args.profile.filter(FilterType::Lanczos3),
+                        dom_color,
+                        tile_alpha_at,
+                        saturation,
+                        args.lerp_alpha,
+                        args.cell_padding,
+                    );
+                    image::imageops::replace(
+                        &mut *reconstructed_img_buffer16.write().unwrap(),
+                        &tile,
+                        x_start as i64,
+                        y_start as i64,
+                    );
+                } else {
+                    let tile = blend_tile(
+                        &placed_tile(&img_list[assignment[idx]], args, grid_x, grid_y),
+                        p_width,
+                        p_height,
+                        args.profile.filter(FilterType::Lanczos3),
+                        dom_color,
+                        tile_alpha_at,
+                        saturation,
+                        args.lerp_alpha,
+                        args.cell_padding,
+                        args.cell_quantize,
+                        args.dither,
+                        args.tile_match().map(|tile_match| (tile_match, portion)),
+                    );
+                    image::imageops::replace(
+                        &mut *reconstructed_img_buffer.write().unwrap(),
+                        &tile,
+                        x_start as i64,
+                        y_start as i64,
+                    );
+                }
+                tile_completed(idx);
+            });
+
+        print_if!(verbose, "Image collaging process complete");
+
+        print_if!(verbose, "Constructing image collage...");
+
+        let mut reconstructed_img = reconstructed_img_buffer.into_inner().unwrap();
+        let mut reconstructed_img16 = reconstructed_img_buffer16.into_inner().unwrap();
+
+        // fill deduplicated cells with the tile rendered for their source cell
+        let cell_width = img_width / grid_cols;
+        let cell_height = img_height / grid_rows;
+        let cell_origin = |idx: usize| {
+            (
+                (idx as u32 % grid_cols) * cell_width,
+                (idx as u32 / grid_cols) * cell_height,
+            )
+        };
+        for idx in (0..image_grid.len()).filter(|&idx| copies_tile(idx)) {
+            let from = cell_origin(cell_sources[idx]);
+            let to = cell_origin(idx);
+            if high_bit_depth {
+                copy_cell(&mut reconstructed_img16, from, to, cell_width, cell_height);
+            } else {
+                copy_cell(&mut reconstructed_img, from, to, cell_width, cell_height);
+            }
+            tile_completed(idx);
+        }
+
+        if args.freq_analysis {
+            let mut uses: HashMap<usize, u32> = HashMap::new();
+            for idx in (0..image_grid.len()).filter(|&idx| is_tile_cell(idx)) {
+                *uses.entry(assignment[idx]).or_default() += 1;
+            }
+            let mean_uses = uses.values().sum::<u32>() as f32 / uses.len().max(1) as f32;
+            print_if!(
+                verbose,
+                "Scaling tile opacity by pool image usage, {:.2} uses per image on average",
+                mean_uses
+            );
+            for idx in (0..image_grid.len()).filter(|&idx| is_tile_cell(idx)) {
+                let factor = usage_opacity(uses[&assignment[idx]] as f32 / mean_uses);
+                if factor == 1.0 {
+                    continue;
+                }
+                let origin = cell_origin(idx);
+                if high_bit_depth {
+                    scale_cell_opacity(
+                        &mut reconstructed_img16,
+                        origin,
+                        cell_width,
+                        cell_height,
+                        factor,
+                    );
+                } else {
+                    scale_cell_opacity(
+                        &mut reconstructed_img,
+                        origin,
+                        cell_width,
+                        cell_height,
+                        factor,
+                    );
+                }
+            }
+        }
+
+        if let Some(zoom) = args.zoom {
+            print_if!(
+                verbose,
+                "Rendering zoom region {}x{} at ({}, {}) with {}x grid density",
+                zoom.width,
+                zoom.height,
+                zoom.x,
+                zoom.y,
+                zoom.factor
+            );
+            let zoomed = self.render_zoom(
+                &img,
+                zoom,
+                img_width / grid_cols,
+                img_height / grid_rows,
+                &candidates,
+                &segments,
+                &alpha_at,
+                args,
+            )?;
+            image::imageops::replace(
+                &mut reconstructed_img,
+                &zoomed,
+                zoom.x as i64,
+                zoom.y as i64,
+            );
+        }
+
+        if let Some(mirror) = args.mirror {
+            print_if!(verbose, "Mirroring collage ({:?})", mirror);
+            mirror.apply(&mut reconstructed_img);
+            mirror.apply(&mut reconstructed_img16);
+        }
+        emit(RecreateEvent::PhaseCompleted(
+            "render",
+            phase_start.elapsed(),
+        ));
+
+        // debug overlays are drawn on an 8 bit copy of the 16 bit collage
+        if high_bit_depth && (args.debug_grid || args.debug_indices) {
+            reconstructed_img = DynamicImage::ImageRgba16(reconstructed_img16.clone()).to_rgba8();
+        }
+
+        if args.debug_grid {
+            let img_names = &self.img_names;
+            let labels: Vec<Option<CellLabel>> = (0..image_grid.len())
+                .map(|idx| {
+                    is_tile_cell(idx).then(|| CellLabel {
+                        source: img_names[assignment[idx]].clone(),
+                        dom_color: lab_to_rgba_u8(cell_colors[idx], None),
+                    })
+                })
+                .collect();
+            let debug_img = debug::draw_debug_grid(
+                &reconstructed_img,
+                img_width / grid_cols,
+                img_height / grid_rows,
+                grid_cols,
+                &labels,
+            );
+            let debug_path = dir.join("debug_grid.png");
+            debug_img.save(&debug_path).with_context(|| {
+                format!("Couldn't save image in path: {}", debug_path.display())
+            })?;
+            print_if!(verbose, "Debug grid written to -> {}", debug_path.display());
+        }
+
+        if args.debug_indices {
+            let indices_img = debug::draw_cell_indices(
+                &reconstructed_img,
+                img_width / grid_cols,
+                img_height / grid_rows,
+                grid_cols,
+                grid_rows,
+            );
+            let indices_path = dir.join("debug_indices.png");
+            indices_img.save(&indices_path).with_context(|| {
+                format!("Couldn't save image in path: {}", indices_path.display())
+            })?;
+            print_if!(
+                verbose,
+                "Debug indices written to -> {}",
+                indices_path.display()
+            );
+        }
+
+        if args.visualise_distances {
+            let pool_colors = &self.pool_colors;
+            let distances: Vec<Option<f32>> = (0..image_grid.len())
+                .map(|idx| {
+                    is_tile_cell(idx)
+                        .then(|| cell_colors[idx].distance(pool_colors[assignment[idx]]))
+                })
+                .collect();
+            let heatmap_img = debug::draw_distance_heatmap(
+                &distances,
+                img_width / grid_cols,
+                img_height / grid_rows,
+                grid_cols,
+            );
+            let heatmap_path = dir.join("distances_heatmap.png");
+            heatmap_img.save(&heatmap_path).with_context(|| {
+                format!("Couldn't save image in path: {}", heatmap_path.display())
+            })?;
+            print_if!(
+                verbose,
+                "Distance heat map written to -> {}",
+                heatmap_path.display()
+            );
+        }
+
+        if args.output_scale != 0.0 {
+            let new_width = (img_width as f32 * args.output_scale).ceil() as u32;
+            let new_height = (img_height as f32 * args.output_scale).ceil() as u32;
+            print_if!(
+                verbose,
+                "Scaling output image to {}x{}",
+                new_width,
+                new_height
+            );
+            if high_bit_depth {
+                reconstructed_img16 = image::imageops::resize(
+                    &reconstructed_img16,
+                    new_width,
+                    new_height,
+                    args.profile.filter(FilterType::CatmullRom),
+                );
+            } else {
+                reconstructed_img = image::imageops::resize(
+                    &reconstructed_img,
+                    new_width,
+                    new_height,
+                    args.profile.filter(FilterType::CatmullRom),
+                );
+            }
+        }
+
+        if let Some(size) = args.output_resize_exact {
+            print_if!(
+                verbose,
+                "Resizing output image to {}x{} ({:?})",
+                size.width,
+                size.height,
+                args.output_resize_mode
+            );
+            let filter = args.profile.filter(FilterType::CatmullRom);
+            if high_bit_depth {
+                reconstructed_img16 =
+                    args.output_resize_mode
+                        .apply(&reconstructed_img16, size, filter);
+            } else {
+                reconstructed_img = args
+                    .output_resize_mode
+                    .apply(&reconstructed_img, size, filter);
+            }
+        }
+
+        if args.hue_rotate % 360.0 != 0.0 {
+            print_if!(
+                verbose,
+                "Rotating output hue by {} degrees",
+                args.hue_rotate
+            );
+            postprocess::apply_hue_rotate(&mut reconstructed_img, args.hue_rotate);
+        }
+
+        if let Some(kernel) = &args.kernel {
+            let divisor = args.kernel_divisor.unwrap_or_else(|| {
+                // same normalisation as image::imageops::filter3x3
+                let sum: f32 = kernel.iter().sum();
+                if sum == 0.0 {
+                    1.0
+                } else {
+                    sum
+                }
+            });
+            print_if!(
+                verbose,
+                "Applying 3x3 kernel {:?} with divisor {}",
+                kernel,
+                divisor
+            );
+            reconstructed_img = convolve3x3(&reconstructed_img, kernel, divisor);
+        }
+
+        if let Some(grid_lines) = args.grid_lines {
+            print_if!(
+                verbose,
+                "Drawing {}px grid lines in {}",
+                grid_lines.width,
+                rgba_to_hex(grid_lines.color)
+            );
+            draw_grid_lines(&mut reconstructed_img, grid_cols, grid_rows, grid_lines);
+        }
+
+        let mut output_img = if high_bit_depth {
+            DynamicImage::ImageRgba16(reconstructed_img16)
+        } else {
+            DynamicImage::ImageRgba8(reconstructed_img)
+        };
+        if let Some(background) = args.flatten {
+            print_if!(
+                verbose,
+                "Flattening collage onto {}",
+                rgba_to_hex(background)
+            );
+            output_img = flatten(output_img, background);
+        }
+        let output_img = match (args.output_channels, high_bit_depth) {
+            (OutputChannels::Rgba, _) => output_img,
+            (OutputChannels::Rgb, false) => DynamicImage::ImageRgb8(output_img.to_rgb8()),
+            (OutputChannels::Rgb, true) => DynamicImage::ImageRgb16(output_img.to_rgb16()),
+        };
+        let (output_img, palette) = match args.palette_quantize {
+            Some(colors) => {
+                let mut quantized = output_img.to_rgba8();
+                let palette = postprocess::quantize(&mut quantized, colors, args.dither);
+                print_if!(
+                    verbose,
+                    "Quantized output image to {} colors{}",
+                    palette.len(),
+                    if args.dither { " with dithering" } else { "" }
+                );
+                let quantized = match args.output_channels {
+                    OutputChannels::Rgba => DynamicImage::ImageRgba8(quantized),
+                    OutputChannels::Rgb => {
+                        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(quantized).to_rgb8())
+                    }
+                };
+                (quantized, Some(palette))
+            }
+            None => (output_img, None),
+        };
+
+        Ok(Collage {
+            image: output_img,
+            grid_cols,
+            grid_rows,
+            cell_colors,
+            quality_score,
+            palette,
+        })
+    }
+}
+
+/// Builds a collage of the encoded reference image `ref_img` out of the encoded pool images in `tiles`
+/// and returns it encoded as PNG.
+/// Options besides the grid size and `alpha` keep their command line defaults, and nothing is printed.
+pub fn collage_png<B: AsRef<[u8]>>(
+    ref_img: &[u8],
+    tiles: &[B],
+    cols: u32,
+    rows: u32,
+    alpha: f32,
+) -> Result<Vec<u8>> {
+    let mut args = Args::library_defaults("", "");
+    args.cols = cols;
+    args.rows = rows;
+    args.alpha = alpha;
+    check_args(&args)?;
+
+    let recreate = Recreate::new();
+    recreate.read_memory_to_vec(tiles, &args)?;
+    let img = image::load_from_memory(ref_img).context("Couldn't decode reference image")?;
+    recreate.collage_to_png(img, &args)
+}
+
+/// Builds a collage of the reference image at `ref_path` out of the pool images in the directory `dir`
+/// and returns it instead of saving it.
+/// Options besides the grid size and `alpha` keep their command line defaults, without --verbose.
+pub fn collage_image(
+    dir: &str,
+    ref_path: &str,
+    cols: u32,
+    rows: u32,
+    alpha: f32,
+) -> Result<DynamicImage> {
+    let mut args = Args::library_defaults(dir, ref_path);
+    args.cols = cols;
+    args.rows = rows;
+    args.alpha = alpha;
+
+    let recreate = Recreate::new();
+    recreate.read_dir_to_vec(dir, ref_file_name(ref_path), &args)?;
+    recreate.collage_in_memory(&args)
+}
+
+/// Runs the command line tool with the process arguments.
+pub fn run() -> Result<()> {
+    // Start the timer
+    let start = Instant::now();
+
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    if let Some(Command::DominantColor(command)) = &cli.command {
+        return print_dominant_color(command);
+    }
+    let mut args = cli
+        .args
+        .expect("the collage options are required without a subcommand");
+    args.apply_profile(&matches);
+    if args.deterministic {
+        if args.threads.is_some_and(|threads| threads != 1) {
+            return Err(anyhow!("--deterministic runs on a single thread, it can't be combined with --threads other than 1"));
+        }
+        args.threads = Some(1);
+        args.seed.get_or_insert(0);
+    }
+    #[cfg(feature = "parallel")]
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.into())
+            .build_global()?;
+    }
+    if args.output.as_deref() == Some("-") {
+        STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+    if args.watch && args.r#ref == "-" {
+        return Err(anyhow!(
+            "--watch can't read --ref - from standard input again"
+        ));
+    }
+    let ref_name = ref_file_name(&args.r#ref);
+
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = &args.s3_bucket {
+        status!(
+            "downloading images from s3://{}/{}...",
+            bucket,
+            args.s3_prefix
+        );
+        let downloaded = s3::sync_prefix(
+            bucket,
+            &args.s3_prefix,
+            args.s3_region.as_deref(),
+            Path::new(&args.dir),
+        )?;
+        print_if!(
+            args.verbose,
+            "Downloaded {} new images into {}",
+            downloaded,
+            args.dir
+        );
+    }
+
+    let recreate = Recreate::new();
+    recreate.read_dir_to_vec(&args.dir, ref_name, &args)?;
+    if let Some(manifest_path) = &args.manifest {
+        recreate.read_manifest_to_vec(manifest_path, &args)?;
+    }
+    if let Some(mix) = &args.mix_pool {
+        recreate.read_mix_pool(&mix.dir, ref_name, &args)?;
+    }
+    if let Some(csv_path) = &args.pool_stats_csv {
+        recreate.write_pool_stats_csv(csv_path, &args)?;
+    }
+    if args.shuffle_pool_order {
+        recreate.shuffle_pool(&args);
+    }
+    build_and_report(&recreate, &args, start)?;
+
+    if args.watch {
+        watch::watch(&recreate, &args, ref_name)?;
+    }
+
+    Ok(())
+}
+
+/// Builds the collage and writes the HTML report if one was requested, timing the run from `start`.
+fn build_and_report(recreate: &Recreate, args: &Args, start: Instant) -> Result<()> {
+    if !args.compare_strategies.is_empty() {
+        return compare_strategies(recreate, args, start);
+    }
+    let stats = recreate.collage(args)?;
+
+    // Calculate the elapsed time
+    let duration = start.elapsed();
+
+    status!("Time taken: {:?}", duration);
+
+    if let Some(stats_path) = &args.stats_json {
+        report::write_stats_json(&stats, duration, stats_path)?;
+        print_if!(args.verbose, "Run statistics written to -> {}", stats_path);
+    }
+    if let Some(report_path) = &args.report_html {
+        HtmlReportWriter::new(stats, duration).write(report_path)?;
+        print_if!(args.verbose, "HTML report written to -> {}", report_path);
+    }
+
+    Ok(())
+}
+
+/// Runs --compare-strategies: builds a collage with every listed strategy and writes output_compare.png next to them.
+fn compare_strategies(recreate: &Recreate, args: &Args, start: Instant) -> Result<()> {
+    let mut collages = Vec::new();
+    let mut labels = Vec::new();
+    for &strategy in &args.compare_strategies {
+        status!("building the {} collage...", strategy);
+        let strategy_args = Args {
+            strategy,
+            ..args.clone()
+        };
+        let stats = recreate.collage_named(&strategy_args, &format!("output_{}", strategy))?;
+        let collage = open(&stats.output_path)
+            .with_context(|| format!("Couldn't open image in path: {}", stats.output_path))?;
+        collages.push(collage.to_rgba8());
+        labels.push(strategy.to_string());
+    }
+
+    let compare_path = output_dir(&args.r#ref).join("output_compare.png");
+    debug::draw_comparison(&collages, &labels)
+        .save(&compare_path)
+        .with_context(|| format!("Couldn't save image in path: {}", compare_path.display()))?;
+    status!(
+        "Strategy comparison written to -> {}",
+        compare_path.display()
+    );
+    status!("Time taken: {:?}", start.elapsed());
+
+    Ok(())
+}
+
+/// Runs the dominant-color subcommand.
+fn print_dominant_color(command: &DominantColorArgs) -> Result<()> {
+    let img = load_pool_image(
+        Path::new(&command.image),
+        Tonemapping::Reinhard.mapper(),
+        None,
+        false,
+    )
+    .with_context(|| format!("Couldn't open image in specified path: {}", command.image))?;
+    let params = KmeansParams {
+        clusters: command.clusters,
+        max_iter: command.iterations,
+        ..Profile::Standard.kmeans()
+    };
+    // the same thumbnail size as for pool images, so the colors match
+    let lab = calc_dominant_color(img.thumbnail(64, 64).to_rgb8().into_raw(), params);
+    let rgba = lab_to_rgba_u8(lab, None);
+    let Rgba([r, g, b, _]) = rgba;
+
+    let lab_text = format!("{:.2} {:.2} {:.2}", lab.l, lab.a, lab.b);
+    match command.format {
+        None => println!("Lab: {}\nHex: {}", lab_text, rgba_to_hex(rgba)),
+        Some(ColorFormat::Hex) => println!("{}", rgba_to_hex(rgba)),
+        Some(ColorFormat::Lab) => println!("{}", lab_text),
+        Some(ColorFormat::Rgb) => println!("{} {} {}", r, g, b),
+        Some(ColorFormat::Json) => println!(
+            "{}",
+            serde_json::json!({
+                "lab": [lab.l, lab.a, lab.b],
+                "rgb": [r, g, b],
+                "hex": rgba_to_hex(rgba),
+            })
+        ),
+    }
+    Ok(())
+}
+
+/// Rejects option combinations the collage can't be built with.
+fn check_args(args: &Args) -> Result<()> {
+    if args.output.is_some() && args.output_formats.len() > 1 {
+        return Err(anyhow!(
+            "--output takes a single path, it can't be combined with more than one --output-formats"
+        ));
+    }
+    if args.output.as_deref() == Some("-") && args.report_html.is_some() {
+        return Err(anyhow!(
+            "--report-html embeds the saved collage, it can't be combined with --output -"
+        ));
+    }
+
+    if args.output_bit_depth == OutputBitDepth::Sixteen {
+        let unsupported = [
+            ("--zoom", args.zoom.is_some()),
+            ("--kernel", args.kernel.is_some()),
+            ("--hue-rotate", args.hue_rotate % 360.0 != 0.0),
+            ("--palette-quantize", args.palette_quantize.is_some()),
+            ("--cell-quantize", args.cell_quantize.is_some()),
+            ("--histogram-match", args.histogram_match),
+            ("--color-transfer", args.color_transfer),
+            ("--contrast-transfer", args.contrast_transfer),
+            ("--grid-lines", args.grid_lines.is_some()),
+            (
+                "non png --output-formats",
+                args.output_formats.iter().any(|&f| f != OutputFormat::Png),
+            ),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, used)| *used) {
+            return Err(anyhow!(
+                "--output-bit-depth 16 can't be combined with {}",
+                name
+            ));
+        }
+    }
+
+    if args.mix_pool.is_some() && (args.strategy == Strategy::Anneal || args.refine_passes > 0) {
+        return Err(anyhow!(
+            "--mix-pool can't be combined with the anneal strategy or --refine-passes"
+        ));
+    }
+
+    // the zoomed region is composited in collage coordinates, mirroring would copy it or flip it away
+    if args.zoom.is_some() && args.mirror.is_some() {
+        return Err(anyhow!("--zoom can't be combined with --mirror"));
+    }
+
+    if args.dither && args.palette_quantize.is_none() && args.cell_quantize.is_none() {
+        return Err(anyhow!(
+            "--dither needs --palette-quantize or --cell-quantize"
+        ));
+    }
+
+    if !(0.0..=360.0).contains(&args.hue_rotate) {
+        return Err(anyhow!("--hue-rotate should be between 0 and 360 degrees"));
+    }
+
+    // a png palette holds at most 256 colors
+    if args
+        .palette_quantize
+        .is_some_and(|colors| !(2..=256).contains(&colors))
+    {
+        return Err(anyhow!(
+            "--palette-quantize should be between 2 and 256 colors"
+        ));
+    }
+
+    if let Some(lambda) = args.mmr_lambda {
+        if !(0.0..=1.0).contains(&lambda) {
+            return Err(anyhow!("--mmr-lambda should be between 0 and 1"));
+        }
+        if args.distance_metric != DistanceMetric::Centroid {
+            return Err(anyhow!(
+                "--mmr-lambda compares dominant colors, it can't be combined with --distance-metric {}",
+                args.distance_metric.to_possible_value().unwrap().get_name()
+            ));
+        }
+    }
+
+    if args.strategy == Strategy::Anneal && (args.sa_start_temp <= 0.0 || args.sa_end_temp <= 0.0) {
+        return Err(anyhow!(
+            "--sa-start-temp and --sa-end-temp should be greater than 0"
+        ));
+    }
+
+    Ok(())
+}
+
+fn divide_image_into_grid(
+    image: &mut DynamicImage,
+    grid_width: u32,
+    grid_height: u32,
+) -> Vec<DynamicImage> {
+    let (img_width, img_height) = image.dimensions();
+
+    // Calculate the "ideal" width and height of each grid cell
+    //basically if we want to have m rows and n cols we need to divide the img_width and img_height
+    //by the number of cols and number of rows
+    let cell_width = img_width / grid_width;
+    let cell_height = img_height / grid_height;
+
+    // println!("cell_width: {}, cell_height: {}", cell_width, cell_height);
+
+    let mut grid_cells = Vec::new();
+
+    for y in 0..grid_height {
+        for x in 0..grid_width {
+            // Calculate start and end coordinates for this cell
+            let x_start = x * cell_width;
+            let y_start = y * cell_height;
+
+            // Create the sub-image (portion) for this grid cell
+            let cell_image = image.crop(x_start, y_start, cell_width, cell_height);
+            grid_cells.push(cell_image);
+        }
+    }
+
+    // println!(
+    //     "grid len: {}, grid dimensions: {:?}",
+    //     grid_cells.len(),
+    //     grid_cells[0].dimensions()
+    // );
+    grid_cells
+}
+
+/// The pool image as it is placed in the cell at grid position (`grid_x`, `grid_y`), color inverted with --invert-tiles
+/// and, with --tile-mirror-at-edges, flipped horizontally in even columns and vertically in even rows.
+fn placed_tile<'a>(
+    img: &'a DynamicImage,
+    args: &Args,
+    grid_x: u32,
+    grid_y: u32,
+) -> Cow<'a, DynamicImage> {
+    let mut tile = Cow::Borrowed(img);
+    if args.invert_tiles {
+        tile.to_mut().invert();
+    }
+    if args.tile_mirror_at_edges {
+        if grid_x.is_multiple_of(2) {
+            tile = Cow::Owned(tile.fliph());
+        }
+        if grid_y.is_multiple_of(2) {
+            tile = Cow::Owned(tile.flipv());
+        }
+    }
+    tile
+}
+
+/// Blends a pool image, resized to `width`x`height`, with the dominant color of its cell and saturates the result.
+/// `alpha_at` returns the blend factor for each pixel of the tile.
+/// With `lerp_alpha` the alpha channel is blended towards the alpha of `dom_color` as well.
+/// `padding` pixels on every side are filled with `dom_color` and the pool image is resized to fit between them.
+/// With `match_reference` the resized pool image is first matched to that reference cell,
+/// and with `quantize_colors` it is then reduced to that many colors, optionally dithered.
+#[allow(clippy::too_many_arguments)]
+fn blend_tile(
+    tile: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+    dom_color: Rgba<u8>,
+    alpha_at: impl Fn(u32, u32) -> f32,
+    saturation: f32,
+    lerp_alpha: bool,
+    padding: u32,
+    quantize_colors: Option<u8>,
+    dither: bool,
+    match_reference: Option<(TileMatch, &DynamicImage)>,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    // Resize the image to match the current portion dimensions, minus the padding
+    let (pad_x, pad_y) = tile_padding(width, height, padding);
+    let mut resized_img = tile.resize_exact(width - 2 * pad_x, height - 2 * pad_y, filter);
+    if let Some((tile_match, reference)) = match_reference {
+        resized_img = tile_match.apply(resized_img, reference);
+    }
+    if let Some(colors) = quantize_colors {
+        let mut quantized = resized_img.into_rgba8();
+        postprocess::quantize(&mut quantized, colors as u32, dither);
+        resized_img = DynamicImage::ImageRgba8(quantized);
+    }
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (Some(tile_x), Some(tile_y)) = (x.checked_sub(pad_x), y.checked_sub(pad_y)) else {
+            return dom_color;
+        };
+        if tile_x >= resized_img.width() || tile_y >= resized_img.height() {
+            return dom_color;
+        }
+        let pixel = resized_img.get_pixel(tile_x, tile_y);
+        let alpha = alpha_at(x, y);
+        //blend pixel color with dominant color using LERP
+        let p_final = RgbaWrapper(pixel).lerp(RgbaWrapper(dom_color), alpha, lerp_alpha);
+        //saturate pixel
+        let p_final_rgba = p_final.0.to_rgba();
+        Rgba(
+            [
+                p_final_rgba[0],
+                p_final_rgba[1],
+                p_final_rgba[2],
+                p_final_rgba[3],
+            ]
+            .apply(&filters::Saturate(saturation)),
+        )
+    })
+}
+
+/// 16 bit version of `blend_tile`, the tile is resized and blended with 16 bit precision.
+#[allow(clippy::too_many_arguments)]
+fn blend_tile_16(
+    tile: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+    dom_color: Rgba<u8>,
+    alpha_at: impl Fn(u32, u32) -> f32,
+    saturation: f32,
+    lerp_alpha: bool,
+    padding: u32,
+) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+    let (pad_x, pad_y) = tile_padding(width, height, padding);
+    let resized_img = tile
+        .resize_exact(width - 2 * pad_x, height - 2 * pad_y, filter)
+        .into_rgba16();
+    // widen 8 bit channels to 16 bit, 255 -> 65535
+    let dom_color = Rgba(dom_color.0.map(|c| c as u16 * 257));
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (Some(tile_x), Some(tile_y)) = (x.checked_sub(pad_x), y.checked_sub(pad_y)) else {
+            return dom_color;
+        };
+        if tile_x >= resized_img.width() || tile_y >= resized_img.height() {
+            return dom_color;
+        }
+        let pixel = *resized_img.get_pixel(tile_x, tile_y);
+        let alpha = alpha_at(x, y);
+        //blend pixel color with dominant color using LERP
+        let Rgba([r, g, b, a]) = Rgba16Wrapper(pixel)
+            .lerp(Rgba16Wrapper(dom_color), alpha, lerp_alpha)
+            .0;
+        let [r, g, b] = saturate_u16([r, g, b], saturation);
+        Rgba([r, g, b, a])
+    })
+}
+
+/// Horizontal and vertical --cell-padding of a `width`x`height` tile, capped so at least a pixel of the image is left.
+fn tile_padding(width: u32, height: u32, padding: u32) -> (u32, u32) {
+    (
+        padding.min(width.saturating_sub(1) / 2),
+        padding.min(height.saturating_sub(1) / 2),
+    )
+}
+
+// Same bound image_effects uses for its Saturate filter
+const CHROMA_BOUND: f32 = 128.0;
+
+/// 16 bit equivalent of image_effects' `filters::Saturate`, which only works on 8 bit colors.
+fn saturate_u16(rgb: [u16; 3], factor: f32) -> [u16; 3] {
+    let mut color = Lch::from_color(Srgb::from(rgb).into_format::<f32>());
+    color.chroma = if factor >= 0.0 {
+        color.chroma + (CHROMA_BOUND - color.chroma) * factor
+    } else {
+        color.chroma + color.chroma * factor
+    };
+    Srgb::from_color(color).into_format().into()
+}
+
+/// Blends `lines.color` over every tile boundary inside the image.
+/// Boundaries are spread evenly so this also works after the collage has been scaled.
+fn draw_grid_lines(
+    buffer: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    grid_cols: u32,
+    grid_rows: u32,
+    lines: GridLines,
+) {
+    let (width, height) = buffer.dimensions();
+    let a = lines.color[3] as f32 / 255.0;
+    let mut blend = |x: u32, y: u32| {
+        let pixel = buffer.get_pixel_mut(x, y);
+        for c in 0..3 {
+            pixel[c] = (lines.color[c] as f32 * a + pixel[c] as f32 * (1.0 - a)).round() as u8;
+        }
+        pixel[3] = pixel[3].max(lines.color[3]);
+    };
+
+    // lines are centred on each boundary
+    let line_span = |boundary: u32, limit: u32| {
+        let start = boundary.saturating_sub(lines.width / 2);
+        start..(start + lines.width).min(limit)
+    };
+
+    for col in 1..grid_cols {
+        let boundary = (col as u64 * width as u64 / grid_cols as u64) as u32;
+        for x in line_span(boundary, width) {
+            for y in 0..height {
+                blend(x, y);
+            }
+        }
+    }
+    for row in 1..grid_rows {
+        let boundary = (row as u64 * height as u64 / grid_rows as u64) as u32;
+        for y in line_span(boundary, height) {
+            for x in 0..width {
+                blend(x, y);
+            }
+        }
+    }
+}
+
+/// Number of distinct pool image indices in `tiles`.
+fn count_unique(tiles: impl Iterator<Item = usize>) -> usize {
+    let mut unique: Vec<usize> = tiles.collect();
+    unique.sort_unstable();
+    unique.dedup();
+    unique.len()
+}
+
+fn enhance_local_contrast(image: &DynamicImage, factor: f32, sigma: f32) -> DynamicImage {
+    let original = image.to_rgb8();
+    let blurred = image::imageops::blur(&original, sigma);
+
+    // add the high-pass detail (original - blurred) back on top of the original
+    let enhanced = ImageBuffer::from_fn(original.width(), original.height(), |x, y| {
+        let o = original.get_pixel(x, y);
+        let b = blurred.get_pixel(x, y);
+        o.map2(b, |o, b| {
+            let o = o as f32;
+            (o + (o - b as f32) * factor).clamp(0.0, 255.0) as u8
+        })
+    });
+
+    DynamicImage::ImageRgb8(enhanced)
+}
+
+/// Averages every block_size x block_size block of the image, the blocks at the right and bottom edges may be smaller.
+fn pixelate(image: &DynamicImage, block_size: u32) -> DynamicImage {
+    let mut pixelated = image.to_rgba8();
+    let (width, height) = pixelated.dimensions();
+
+    for block_y in (0..height).step_by(block_size as usize) {
+        for block_x in (0..width).step_by(block_size as usize) {
+            let block_width = block_size.min(width - block_x);
+            let block_height = block_size.min(height - block_y);
+            let mut sum = [0u64; 4];
+            for y in block_y..block_y + block_height {
+                for x in block_x..block_x + block_width {
+                    let pixel = pixelated.get_pixel(x, y);
+                    for (total, &channel) in sum.iter_mut().zip(pixel.0.iter()) {
+                        *total += channel as u64;
+                    }
+                }
+            }
+
+            let count = (block_width * block_height) as u64;
+            let mean = Rgba(sum.map(|total| ((total + count / 2) / count) as u8));
+            for y in block_y..block_y + block_height {
+                for x in block_x..block_x + block_width {
+                    pixelated.put_pixel(x, y, mean);
+                }
+            }
+        }
+    }
+
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(pixelated)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(pixelated).to_rgb8())
+    }
+}
+
+/// Grid of `pool_size * max_reuse` cells for --auto-grid: of the column and row counts multiplying to exactly that many cells
+/// and fitting in the image, the pair whose ratio is closest to the aspect ratio of the image. Pairs dividing the image
+/// come first, as the grid keeps its number of cells when it isn't adjusted to divide the image afterwards.
+/// Falls back to the closest grid of about that many cells when no exact factorisation fits.
+fn auto_grid(pool_size: usize, img_width: u32, img_height: u32, max_reuse: u32) -> (u32, u32) {
+    let cells = (pool_size as u64 * max_reuse as u64).max(1);
+    let aspect = (img_width as f64 / img_height as f64).ln();
+    let aspect_error = |(cols, rows): (u64, u64)| ((cols as f64 / rows as f64).ln() - aspect).abs();
+    let fits = |&(cols, rows): &(u64, u64)| cols <= img_width as u64 && rows <= img_height as u64;
+    let divides_image = |(cols, rows): (u64, u64)| {
+        (img_width as u64).is_multiple_of(cols) && (img_height as u64).is_multiple_of(rows)
+    };
+
+    let exact = (1..)
+        .take_while(|divisor| divisor * divisor <= cells)
+        .filter(|divisor| cells.is_multiple_of(*divisor))
+        .flat_map(|divisor| [(divisor, cells / divisor), (cells / divisor, divisor)])
+        .filter(fits)
+        .min_by(|&a, &b| {
+            divides_image(b)
+                .cmp(&divides_image(a))
+                .then(aspect_error(a).total_cmp(&aspect_error(b)))
+        });
+    let (cols, rows) = exact.unwrap_or_else(|| {
+        let cols = (cells as f64 * img_width as f64 / img_height as f64)
+            .sqrt()
+            .round() as u64;
+        let cols = cols.clamp(1, img_width as u64);
+        (cols, cells.div_ceil(cols).clamp(1, img_height as u64))
+    });
+    (cols as u32, rows as u32)
+}
+
+fn next_divisor(n: u32, start: u32) -> Result<u32> {
+    if start > n {
+        return Err(anyhow!("Grid value should be less that {}", n));
+    }
+
+    if n.is_multiple_of(start) {
+        return Ok(start);
+    }
+
+    // a prime's only divisors are 1 and itself, no need to scan up to it
+    if start > 1 && is_prime(n) {
+        return Ok(n);
+    }
+
+    // u64 so stepping past the last candidate can't overflow for n close to u32::MAX
+    let n = u64::from(n);
+    match (u64::from(start) + 1..=n).find(|&i| n.is_multiple_of(i)) {
+        Some(divisor) => Ok(divisor as u32), // Return the next divisor
+        None => Ok(start),
+    }
+}
+
+fn is_prime(n: u32) -> bool {
+    let n = u64::from(n);
+    n >= 2
+        && (2..)
+            .take_while(|i| i * i <= n)
+            .all(|i| !n.is_multiple_of(i))
+}
+
+/// Color a tile is blended towards for a cell with the dominant color `dom_color` and alpha `dom_alpha`.
+fn blend_target(dom_color: Lab, dom_alpha: Option<u8>, mode: BlendMode) -> Rgba<u8> {
+    match mode {
+        BlendMode::Normal => lab_to_rgba_u8(dom_color, dom_alpha),
+        BlendMode::Complementary => lab_to_rgba_u8(complementary_lab(dom_color), dom_alpha),
+    }
+}
+
+/// Lab complement of a color: lightness is mirrored and both color axes are flipped.
+/// Neutral greys (a* = b* = 0) stay neutral.
+fn complementary_lab(lab: Lab) -> Lab {
+    Lab::new(100.0 - lab.l, -lab.a, -lab.b)
+}
+
+/// `alpha` defaults to full opacity.
+fn lab_to_rgba_u8(lab: Lab, alpha: Option<u8>) -> Rgba<u8> {
+    // Convert Lab to XYZ
+    let xyz: Xyz = Xyz::from_color(lab);
+
+    // Convert XYZ to Srgb (RGB)
+    let rgb: Srgb = Srgb::from_color(xyz);
+
+    // Clamp RGB values and convert to u8
+    let r = (rgb.red * 255.0).clamp(0.0, 255.0) as u8;
+    let g = (rgb.green * 255.0).clamp(0.0, 255.0) as u8;
+    let b = (rgb.blue * 255.0).clamp(0.0, 255.0) as u8;
+
+    Rgba([r, g, b, alpha.unwrap_or(255)])
+}
+
+/// Base temperature at `step` of the annealing schedule, falls geometrically from --sa-start-temp to --sa-end-temp.
+fn sa_temperature(step: u64, args: &Args) -> f32 {
+    let progress = step as f32 / args.sa_iterations.max(1) as f32;
+    args.sa_start_temp * (args.sa_end_temp / args.sa_start_temp).powf(progress)
+}
+
+/// Image in `sorted` (lightness, image index pairs in ascending lightness) whose lightness is closest to `lightness`.
+fn closest_lightness(sorted: &[(f32, usize)], lightness: f32) -> usize {
+    let next = sorted.partition_point(|&(l, _)| l < lightness);
+    let below = next.checked_sub(1).map(|idx| sorted[idx]);
+    let above = sorted.get(next).copied();
+    match (below, above) {
+        (Some(below), Some(above)) if lightness - below.0 <= above.0 - lightness => below.1,
+        (_, Some(above)) => above.1,
+        (Some(below), None) => below.1,
+        (None, None) => 0,
+    }
+}
+
+/// Metropolis acceptance of a change in energy of `delta` at `temperature`.
+fn accept(delta: f32, temperature: f32, rng: &mut StdRng) -> bool {
+    delta <= 0.0 || rng.gen::<f32>() < (-delta / temperature).exp()
+}
+
+/// Marks every cell of the `grid_cols`x`grid_rows` grid whose strongest Sobel gradient magnitude
+/// is above `threshold`, in row-major order.
+fn detect_edge_cells(
+    img: &DynamicImage,
+    grid_cols: u32,
+    grid_rows: u32,
+    threshold: u16,
+) -> Vec<bool> {
+    let gradients = sobel_gradients(&img.to_luma8());
+    let cell_width = img.width() / grid_cols;
+    let cell_height = img.height() / grid_rows;
+
+    (0..grid_cols * grid_rows)
+        .map(|idx| {
+            let x_start = (idx % grid_cols) * cell_width;
+            let y_start = (idx / grid_cols) * cell_height;
+            (y_start..y_start + cell_height).any(|y| {
+                (x_start..x_start + cell_width).any(|x| gradients.get_pixel(x, y)[0] > threshold)
+            })
+        })
+        .collect()
+}
+
+/// Maps every cell to the first earlier cell whose dominant color is within `threshold` (Lab distance)
+/// and has the same alpha, cells without such a match map to themselves.
+/// Cells that aren't built (see `--mirror`) are never used as a source.
+fn dedup_cells(
+    cell_colors: &[Lab],
+    cell_alphas: &[Option<u8>],
+    threshold: f32,
+    is_built_cell: impl Fn(usize) -> bool,
+) -> Vec<usize> {
+    let threshold_squared = threshold * threshold;
+    let mut sources: Vec<usize> = Vec::new();
+
+    (0..cell_colors.len())
+        .map(|idx| {
+            if !is_built_cell(idx) {
+                return idx;
+            }
+            let source = sources.iter().copied().find(|&source| {
+                cell_alphas[source] == cell_alphas[idx]
+                    && cell_colors[source].distance_squared(cell_colors[idx]) <= threshold_squared
+            });
+            source.unwrap_or_else(|| {
+                sources.push(idx);
+                idx
+            })
+        })
+        .collect()
+}
+
+/// Copies the `width`x`height` cell at `from` to `to` within the same buffer.
+fn copy_cell<P: image::Pixel + 'static>(
+    buffer: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    from: (u32, u32),
+    to: (u32, u32),
+    width: u32,
+    height: u32,
+) {
+    let cell = image::imageops::crop_imm(buffer, from.0, from.1, width, height).to_image();
+    image::imageops::replace(buffer, &cell, to.0 as i64, to.1 as i64);
+}
+
+/// Opacity factor --freq-analysis gives the tiles of a pool image used `usage_ratio` times as often as the average one.
+fn usage_opacity(usage_ratio: f32) -> f32 {
+    if usage_ratio > 2.0 {
+        1.0 - FREQ_OPACITY_STEP
+    } else if usage_ratio < 0.5 {
+        1.0 + FREQ_OPACITY_STEP
+    } else {
+        1.0
+    }
+}
+
+/// Multiplies the alpha of the `width`x`height` cell at `origin` by `factor`, clamped to the channel range.
+/// The buffer holds straight alpha, so the color channels are left as they are.
+fn scale_cell_opacity<S>(
+    buffer: &mut ImageBuffer<Rgba<S>, Vec<S>>,
+    origin: (u32, u32),
+    width: u32,
+    height: u32,
+    factor: f32,
+) where
+    Rgba<S>: image::Pixel<Subpixel = S>,
+    S: image::Primitive + Into<f32> + Clamp<f32>,
+{
+    for y in origin.1..origin.1 + height {
+        for x in origin.0..origin.0 + width {
+            let pixel = buffer.get_pixel_mut(x, y);
+            pixel.0[3] = S::clamp(pixel.0[3].into() * factor);
+        }
+    }
+}
+
+/// Rotates `img` clockwise by `degrees`. Quarter turns are exact and swap the dimensions for 90 and 270,
+/// other angles keep the dimensions, interpolate bilinearly and fill the uncovered corners with `fill`.
+fn rotate_image(img: &DynamicImage, degrees: f32, fill: Rgba<u8>) -> DynamicImage {
+    match degrees.rem_euclid(360.0) {
+        90.0 => img.rotate90(),
+        180.0 => img.rotate180(),
+        270.0 => img.rotate270(),
+        degrees => DynamicImage::ImageRgba8(rotate_about_center(
+            &img.to_rgba8(),
+            degrees.to_radians(),
+            Interpolation::Bilinear,
+            fill,
+        )),
+    }
+}
+
+/// Average alpha of an image, images without an alpha channel are fully opaque.
+fn mean_alpha(img: &DynamicImage) -> u8 {
+    if !img.color().has_alpha() {
+        return 255;
+    }
+    let rgba = img.to_rgba8();
+    let sum: u64 = rgba.pixels().map(|pixel| pixel[3] as u64).sum();
+    (sum / rgba.pixels().len().max(1) as u64) as u8
+}
+
+/// Evaluates the cubic bezier from (0, 0) to (1, 1) with the control points `[x1, y1, x2, y2]` at `x`.
+/// x grows monotonically along the curve, so its parameter is found by bisection.
+fn cubic_bezier([x1, y1, x2, y2]: [f32; 4], x: f32) -> f32 {
+    let at = |p1: f32, p2: f32, t: f32| {
+        let u = 1.0 - t;
+        3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t
+    };
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..32 {
+        let mid = (low + high) / 2.0;
+        if at(x1, x2, mid) < x {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    at(y1, y2, (low + high) / 2.0)
+}
+
+/// Parses a color given as a hex code (#RRGGBB or #RRGGBBAA, the leading # is optional).
+fn parse_hex_color(value: &str) -> Result<Rgba<u8>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "Invalid color {}, expected a hex code like #RRGGBB or #RRGGBBAA",
+            value
+        ));
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    let a = if hex.len() == 8 { channel(6) } else { 255 };
+    Ok(Rgba([channel(0), channel(2), channel(4), a]))
+}
+
+/// Parses a mix pool given as dir,l-threshold.
+fn parse_mix_pool(value: &str) -> Result<MixPool> {
+    // split on the last comma so directory names may contain commas
+    let (dir, threshold) = value
+        .rsplit_once(',')
+        .ok_or_else(|| anyhow!("Invalid mix pool {}, expected dir,l-threshold", value))?;
+    let threshold = threshold.trim().parse::<f32>().with_context(|| {
+        format!(
+            "Invalid mix pool {}, {} is not a lightness threshold",
+            value, threshold
+        )
+    })?;
+    Ok(MixPool {
+        dir: dir.to_owned(),
+        threshold,
+    })
+}
+
+/// Parses an output resolution given as WxH.
+fn parse_output_size(value: &str) -> Result<OutputSize> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| anyhow!("Invalid output size {}, expected WxH", value))?;
+    let side = |side: &str| {
+        side.trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|&side| side > 0)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid output size {}, {} is not a positive pixel value",
+                    value,
+                    side
+                )
+            })
+    };
+    Ok(OutputSize {
+        width: side(width)?,
+        height: side(height)?,
+    })
+}
+
+/// Parses a zoom region given as x,y,w,h,scale_factor.
+fn parse_zoom(value: &str) -> Result<Zoom> {
+    let parts: Vec<&str> = value.split(',').map(str::trim).collect();
+    if parts.len() != 5 {
+        return Err(anyhow!(
+            "Invalid zoom {}, expected x,y,w,h,scale_factor",
+            value
+        ));
+    }
+
+    let coord = |i: usize| {
+        parts[i]
+            .parse::<u32>()
+            .with_context(|| format!("Invalid zoom {}, {} is not a pixel value", value, parts[i]))
+    };
+    let factor = parts[4]
+        .parse::<f32>()
+        .with_context(|| format!("Invalid zoom {}, {} is not a scale factor", value, parts[4]))?;
+    if factor <= 0.0 {
+        return Err(anyhow!(
+            "Invalid zoom {}, scale_factor should be greater than 0",
+            value
+        ));
+    }
+
+    Ok(Zoom {
+        x: coord(0)?,
+        y: coord(1)?,
+        width: coord(2)?,
+        height: coord(3)?,
+        factor,
+    })
+}
+
+/// Parses grid lines given as <color>,<width>, the width is optional and defaults to 1.
+fn parse_grid_lines(value: &str) -> Result<GridLines> {
+    let (color, width) = match value.split_once(',') {
+        Some((color, width)) => {
+            let width = width.trim().parse::<u32>().with_context(|| {
+                format!(
+                    "Invalid grid lines {}, {} is not a pixel width",
+                    value, width
+                )
+            })?;
+            (color, width)
+        }
+        None => (value, 1),
+    };
+
+    Ok(GridLines {
+        color: parse_hex_color(color.trim())?,
+        width,
+    })
+}
+
+/// Parses the four comma separated control points x1,y1,x2,y2 of a cubic bezier, x1 and x2 have to be in 0-1.
+fn parse_bezier(value: &str) -> Result<[f32; 4]> {
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .with_context(|| format!("Invalid bezier {}, values should be numbers", value))?;
+    let points: [f32; 4] = values.try_into().map_err(|values: Vec<f32>| {
+        anyhow!(
+            "Invalid bezier {}, expected 4 values but got {}",
+            value,
+            values.len()
+        )
+    })?;
+
+    if !(0.0..=1.0).contains(&points[0]) || !(0.0..=1.0).contains(&points[2]) {
+        return Err(anyhow!(
+            "Invalid bezier {}, x1 and x2 should be between 0 and 1",
+            value
+        ));
+    }
+    Ok(points)
+}
+
+/// Parses nine comma separated floats into a row-major 3x3 kernel.
+fn parse_kernel(value: &str) -> Result<[f32; 9]> {
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .with_context(|| format!("Invalid kernel {}, values should be numbers", value))?;
+
+    values.try_into().map_err(|values: Vec<f32>| {
+        anyhow!(
+            "Invalid kernel {}, expected 9 values but got {}",
+            value,
+            values.len()
+        )
+    })
+}
+
+/// Applies a 3x3 convolution kernel to the color channels of an image, leaving alpha untouched.
+/// Unlike image::imageops::filter3x3 the edge pixels are convolved too (by clamping to the border)
+/// and the divisor can be chosen independently of the kernel.
+fn convolve3x3(
+    image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    kernel: &[f32; 9],
+    divisor: f32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let mut sum = [0.0f32; 3];
+        for (k, weight) in kernel.iter().enumerate() {
+            let sx = (x as i64 + (k % 3) as i64 - 1).clamp(0, width as i64 - 1) as u32;
+            let sy = (y as i64 + (k / 3) as i64 - 1).clamp(0, height as i64 - 1) as u32;
+            let pixel = image.get_pixel(sx, sy);
+            for c in 0..3 {
+                sum[c] += pixel[c] as f32 * weight;
+            }
+        }
+
+        let Rgba([_, _, _, a]) = *image.get_pixel(x, y);
+        let channel = |c: usize| (sum[c] / divisor).clamp(0.0, 255.0) as u8;
+        Rgba([channel(0), channel(1), channel(2), a])
+    })
+}
+
+/// File names marked with a true `include` column in a pool filter CSV.
+/// The CSV needs a header row with `filename` and `include` columns, any other columns are ignored.
+fn read_pool_filter_csv(csv_path: &str) -> Result<HashSet<String>> {
+    let mut reader = csv::Reader::from_path(csv_path)
+        .with_context(|| format!("Couldn't open pool filter CSV in path: {}", csv_path))?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("Pool filter CSV {} has no {} column", csv_path, name))
+    };
+    let (filename_col, include_col) = (column("filename")?, column("include")?);
+
+    let mut included = HashSet::new();
+    for record in reader.records() {
+        let record =
+            record.with_context(|| format!("Invalid row in pool filter CSV {}", csv_path))?;
+        let include = record.get(include_col).unwrap_or_default().trim();
+        if ["true", "1", "yes"]
+            .iter()
+            .any(|value| include.eq_ignore_ascii_case(value))
+        {
+            if let Some(filename) = record.get(filename_col) {
+                included.insert(filename.trim().to_owned());
+            }
+        }
+    }
+
+    Ok(included)
+}
+
+/// Loads the images in the directory `dir_path`, except `ref_img`, along with their file names, sorted by file name.
+fn load_pool_dir(
+    dir_path: &str,
+    ref_img: &str,
+    args: &Args,
+) -> Result<(Vec<DynamicImage>, Vec<String>)> {
+    load_pool_dir_with_tone_mapper(dir_path, ref_img, args, args.tonemapping.mapper())
+}
+
+/// `load_pool_dir` with HDR images tone mapped by `tone_mapper` rather than the --tonemapping operator.
+fn load_pool_dir_with_tone_mapper(
+    dir_path: &str,
+    ref_img: &str,
+    args: &Args,
+    tone_mapper: &dyn ToneMapper,
+) -> Result<(Vec<DynamicImage>, Vec<String>)> {
+    status!("pulling images...");
+    const NTHREADS: usize = 20;
+
+    let files = fs::read_dir(dir_path).with_context(|| {
+        format!(
+            "Couldn't read directory in specified path: {}, do well to check the path again.",
+            dir_path
+        )
+    })?;
+
+    // Collect files before threads (avoid borrowing issues)
+    // sorted so the pool order doesn't depend on the filesystem
+    let mut file_paths: Vec<_> = files
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    file_paths.sort();
+
+    if let Some(csv_path) = &args.pool_filter_csv {
+        let included = read_pool_filter_csv(csv_path)?;
+        file_paths.retain(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| included.contains(name))
+        });
+        print_if!(
+            args.verbose,
+            "{} images selected by {}",
+            file_paths.len(),
+            csv_path
+        );
+    }
+
+    // Split the file paths into chunks for each thread
+    let nthreads = args.threads.map_or(NTHREADS, usize::from);
+    let chunk_size = file_paths.len().div_ceil(nthreads).max(1);
+    let file_chunks: Vec<_> = file_paths.chunks(chunk_size).collect();
+
+    // scoped so the threads can borrow the tone mapper
+    let mut images = Vec::new();
+    let mut names = Vec::new();
+    let mut errors = Vec::new();
+    thread::scope(|scope| {
+        let mut children = vec![];
+
+        // Spawn threads
+        for chunk in file_chunks {
+            let chunk = chunk.to_vec(); // Clone file chunk for this thread
+            let ref_img_cp = ref_img.to_owned();
+            let alpha_fill = args.strip_alpha.then_some(args.alpha_fill);
+            let respect_icc = args.respect_icc_profile;
+            let tolerate_errors = args.tolerate_errors;
+
+            children.push(scope.spawn(
+                move || -> Result<(Vec<DynamicImage>, Vec<String>, Vec<anyhow::Error>)> {
+                    let mut local_vec = Vec::new(); // Local vec to batch insertions
+                    let mut local_names = Vec::new();
+                    let mut local_errors = Vec::new();
+
+                    for file_path in chunk {
+                        let file_name = file_path.file_name().unwrap();
+                        let file_path_str = file_name.to_str().unwrap();
+
+                        if file_path_str == ref_img_cp.as_str() {
+                            continue;
+                        }
+
+                        // with --tolerate-errors a bad file is skipped and the rest of the chunk still loads
+                        match load_pool_image(&file_path, tone_mapper, alpha_fill, respect_icc) {
+                            Ok(img) => {
+                                local_vec.push(img);
+                                local_names.push(file_path_str.to_owned());
+                            }
+                            Err(e) if tolerate_errors => local_errors.push(e),
+                            Err(e) => return Err(e),
+                        }
+                    }
+
+                    Ok((local_vec, local_names, local_errors))
+                },
+            ));
+        }
+
+        // Join all threads and handle potential errors
+        // results are inserted in chunk order, so the pool is in file name order whichever thread finishes first
+        for child in children {
+            match child.join().unwrap() {
+                Ok((local_vec, local_names, skipped)) => {
+                    images.extend(local_vec);
+                    names.extend(local_names);
+                    for e in skipped {
+                        eprintln!("Skipped pool image: {:#}", e);
+                    }
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+    });
+    if !errors.is_empty() {
+        // without --tolerate-errors each thread stops at its first failure, so this lists one error per failed chunk
+        let failures: Vec<String> = errors.iter().map(|e| format!("{:#}", e)).collect();
+        return Err(anyhow!(
+            "{}\nPass --tolerate-errors to build the collage from the pool images that did load",
+            failures.join("\n")
+        ));
+    }
+
+    Ok((images, names))
+}
+
+/// Opens a pool image the way every pool image is prepared: upright, tone mapped to 8 bit if it is HDR and
+/// flattened onto `alpha_fill` if one is given.
+fn load_pool_image(
+    path: &Path,
+    tone_mapper: &dyn ToneMapper,
+    alpha_fill: Option<Rgba<u8>>,
+    respect_icc: bool,
+) -> Result<DynamicImage> {
+    // the content decides the format, downloaded images don't always have an extension
+    let (mut img, icc_profile) = ImageReader::open(path)
+        .and_then(ImageReader::with_guessed_format)
+        .map_err(image::ImageError::IoError)
+        .and_then(ImageReader::into_decoder)
+        .and_then(|mut decoder| {
+            let icc_profile = if respect_icc {
+                decoder.icc_profile()?
+            } else {
+                None
+            };
+            Ok((DynamicImage::from_decoder(decoder)?, icc_profile))
+        })
+        .with_context(|| format!("Couldn't open image in specified path: {}", path.display()))?;
+
+    if let Some(icc_profile) = icc_profile {
+        img = convert_to_srgb(img, &icc_profile);
+    }
+
+    // camera photos are often stored sideways with an EXIF tag saying how to rotate them
+    if let Some(orientation) = exif_orientation(path).and_then(Orientation::from_exif) {
+        img.apply_orientation(orientation);
+    }
+
+    if tonemap::is_hdr(&img) {
+        img = tonemap::tonemap(&img, tone_mapper);
+    }
+
+    if let Some(fill) = alpha_fill {
+        img = strip_alpha(img, fill);
+    }
+
+    Ok(img)
+}
+
+/// Converts `img` from the color space described by the ICC profile `icc_profile` to 8 bit sRGB.
+/// Images whose profile can't be read or converted from (eg. CMYK ones) are returned unchanged.
+fn convert_to_srgb(img: DynamicImage, icc_profile: &[u8]) -> DynamicImage {
+    static SRGB: OnceLock<Box<qcms::Profile>> = OnceLock::new();
+    let srgb = SRGB.get_or_init(|| {
+        let mut srgb = qcms::Profile::new_sRGB();
+        srgb.precache_output_transform();
+        srgb
+    });
+    let Some(profile) = qcms::Profile::new_from_slice(icc_profile, false) else {
+        return img;
+    };
+
+    let data_type = if img.color().has_alpha() {
+        qcms::DataType::RGBA8
+    } else {
+        qcms::DataType::RGB8
+    };
+    let Some(transform) = qcms::Transform::new(&profile, srgb, data_type, qcms::Intent::default())
+    else {
+        return img;
+    };
+    // called through the type, image-effects' Effect trait has an apply method as well
+    if img.color().has_alpha() {
+        let mut buffer = img.into_rgba8();
+        qcms::Transform::apply(&transform, &mut buffer);
+        DynamicImage::ImageRgba8(buffer)
+    } else {
+        let mut buffer = img.into_rgb8();
+        qcms::Transform::apply(&transform, &mut buffer);
+        DynamicImage::ImageRgb8(buffer)
+    }
+}
+
+/// Directory the outputs of a collage of the reference image at `ref_path` are written to, the one it is in.
+/// Collages of a downloaded reference image are written to the working directory.
+fn output_dir(ref_path: &str) -> &Path {
+    if download::is_url(ref_path) {
+        return Path::new(".");
+    }
+    match Path::new(ref_path).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// File name of the reference image at `ref_path`, which is skipped when loading a pool directory it is in.
+fn ref_file_name(ref_path: &str) -> &str {
+    Path::new(ref_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// Opens the reference image, tone mapping it with --ref-hdr.
+/// A URL is downloaded to a temporary file first, removed once the image is decoded unless --keep-temp is set.
+fn open_ref(args: &Args) -> Result<DynamicImage> {
+    if args.r#ref == "-" {
+        let mut bytes = Vec::new();
+        io::stdin()
+            .read_to_end(&mut bytes)
+            .context("Couldn't read ref image from standard input")?;
+        let img = image::load_from_memory(&bytes)
+            .context("Couldn't decode ref image from standard input")?;
+        // there is no extension to go by, the content says whether it is HDR
+        return Ok(if args.ref_hdr && tonemap::is_hdr(&img) {
+            tonemap::tonemap(&img, args.tonemapping.mapper())
+        } else {
+            img
+        });
+    }
+    if !download::is_url(&args.r#ref) {
+        return open_ref_file(&args.r#ref, args);
+    }
+
+    let temp = args.temp_dir().join(format!(
+        "recreate-{}-{}",
+        std::process::id(),
+        download::file_name(&args.r#ref)
+    ));
+    print_if!(
+        args.verbose,
+        "Downloading ref image {} to {}",
+        args.r#ref,
+        temp.display()
+    );
+    let img = HttpClient::new(None)
+        .download(&args.r#ref, &temp)
+        .with_context(|| format!("Couldn't download ref image {}", args.r#ref))
+        .and_then(|()| open_ref_file(&temp.to_string_lossy(), args));
+    if args.keep_temp {
+        print_if!(
+            args.verbose,
+            "Keeping downloaded ref image {}",
+            temp.display()
+        );
+    } else {
+        let _ = fs::remove_file(&temp);
+    }
+    img
+}
+
+fn open_ref_file(path: &str, args: &Args) -> Result<DynamicImage> {
+    if args.ref_hdr {
+        print_if!(
+            args.verbose,
+            "Tone mapping HDR ref image with {:?}",
+            args.tonemapping
+        );
+        open_hdr(path, args.tonemapping.mapper())
+    } else {
+        open(path).with_context(|| format!("Couldn't open image in specified path: {}", path))
+    }
+}
+
+/// Opens a .hdr or .exr image with the matching decoder and tone maps it to 8 bit.
+fn open_hdr(path: &str, tone_mapper: &dyn ToneMapper) -> Result<DynamicImage> {
+    let format = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("hdr") => ImageFormat::Hdr,
+        Some(ext) if ext.eq_ignore_ascii_case("exr") => ImageFormat::OpenExr,
+        _ => return Err(anyhow!("HDR image {} should be a .hdr or .exr file", path)),
+    };
+
+    let mut reader = ImageReader::open(path)
+        .with_context(|| format!("Couldn't open image in specified path: {}", path))?;
+    reader.set_format(format);
+    let img = reader
+        .decode()
+        .with_context(|| format!("Couldn't decode HDR image in specified path: {}", path))?;
+    Ok(tonemap::tonemap(&img, tone_mapper))
+}
+
+/// EXIF orientation tag of the image file at `path`, if it has one.
+fn exif_orientation(path: &Path) -> Option<u8> {
+    let mut reader = BufReader::new(fs::File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    field
+        .value
+        .get_uint(0)
+        .and_then(|value| u8::try_from(value).ok())
+}
+
+/// Composites an image over a solid `fill` color and drops its alpha channel.
+fn strip_alpha(img: DynamicImage, fill: Rgba<u8>) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let a = pixel[3] as f32 / 255.0;
+        for c in 0..3 {
+            pixel[c] = (pixel[c] as f32 * a + fill[c] as f32 * (1.0 - a)).round() as u8;
+        }
+    }
+
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+}
+
+/// Composites every pixel over an opaque `background` color, the result is fully opaque.
+fn flatten(img: DynamicImage, background: Rgba<u8>) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgba16(mut buffer) => {
+            for pixel in buffer.pixels_mut() {
+                let a = pixel[3] as f32 / 65535.0;
+                for c in 0..3 {
+                    let bg = background[c] as f32 * 257.0;
+                    pixel[c] = (pixel[c] as f32 * a + bg * (1.0 - a)).round() as u16;
+                }
+                pixel[3] = u16::MAX;
+            }
+            DynamicImage::ImageRgba16(buffer)
+        }
+        img => {
+            let mut buffer = img.into_rgba8();
+            for pixel in buffer.pixels_mut() {
+                let a = pixel[3] as f32 / 255.0;
+                for c in 0..3 {
+                    pixel[c] =
+                        (pixel[c] as f32 * a + background[c] as f32 * (1.0 - a)).round() as u8;
+                }
+                pixel[3] = u8::MAX;
+            }
+            DynamicImage::ImageRgba8(buffer)
+        }
+    }
+}
+
+fn rgba_to_hex(color: Rgba<u8>) -> String {
+    let Rgba([r, g, b, _]) = color;
+    format!("#{:02X}{:02X}{:02X}", r, g, b)
+}
+
+/// Color of an RGB [u8] buffer according to --blend-target.
+fn cell_color(img_vec: Vec<u8>, args: &Args) -> Lab {
+    match args.blend_target {
+        BlendTarget::Dominant => {
+            let sample_size = args.dominant_sample_size as usize;
+            let img_vec = if sample_size > 0 && img_vec.len() / 3 > sample_size {
+                sample_pixels(&img_vec, sample_size)
+            } else {
+                img_vec
+            };
+            calc_dominant_color(img_vec, args.profile.kmeans())
+        }
+        BlendTarget::Mean => mean_color(img_vec),
+        BlendTarget::Median => median_color(img_vec),
+    }
+}
+
+/// Picks `sample_size` pixels of an RGB [u8] buffer uniformly at random with reservoir sampling.
+/// The generator has a fixed seed, so the same image always gives the same sample.
+fn sample_pixels(img_vec: &[u8], sample_size: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut reservoir: Vec<&[u8]> = Vec::with_capacity(sample_size);
+    for (idx, pixel) in img_vec.chunks_exact(3).enumerate() {
+        if idx < sample_size {
+            reservoir.push(pixel);
+        } else {
+            let slot = rng.gen_range(0..=idx);
+            if slot < sample_size {
+                reservoir[slot] = pixel;
+            }
+        }
+    }
+    reservoir.concat()
+}
+
+fn calc_dominant_color(img_vec: Vec<u8>, params: KmeansParams) -> Lab {
+    let res = kmeans_palette(img_vec, params);
+
+    // We can find the dominant color directly
+    let dominant_color = Lab::get_dominant_color(&res);
+
+    dominant_color.unwrap()
+}
+
+/// Arithmetic mean of the Lab colors of an RGB [u8] buffer.
+fn mean_color(img_vec: Vec<u8>) -> Lab {
+    let pixels = from_component_slice::<Srgb<u8>>(&img_vec);
+    let sum = pixels.iter().fold(Lab::new(0.0, 0.0, 0.0), |sum, rgb| {
+        let lab: Lab = rgb.into_format().into_color();
+        sum + lab
+    });
+    sum / pixels.len().max(1) as f32
+}
+
+/// Per-channel median of the Lab colors of an RGB [u8] buffer. This is not the true median color, which
+/// has no cheap definition in 3 dimensions, but approximates it. With an even number of pixels the two middle
+/// values are averaged, so a half black, half white image is grey.
+fn median_color(img_vec: Vec<u8>) -> Lab {
+    let pixels: Vec<Lab> = from_component_slice::<Srgb<u8>>(&img_vec)
+        .iter()
+        .map(|rgb| rgb.into_format().into_color())
+        .collect();
+    if pixels.is_empty() {
+        return Lab::default();
+    }
+
+    let median = |channel: fn(&Lab) -> f32| {
+        let mut values: Vec<f32> = pixels.iter().map(channel).collect();
+        let mid = values.len() / 2;
+        let (lower, &mut upper, _) = values.select_nth_unstable_by(mid, f32::total_cmp);
+        if pixels.len() % 2 == 0 {
+            // the largest value of the lower half is the other middle one
+            let lower = lower.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            (lower + upper) / 2.0
+        } else {
+            upper
+        }
+    };
+    Lab::new(
+        median(|lab| lab.l),
+        median(|lab| lab.a),
+        median(|lab| lab.b),
+    )
+}
+
+/// Clusters the colors of an RGB [u8] buffer into (up to) `params.clusters` Lab colors, each with the share of pixels it covers.
+fn kmeans_palette(img_vec: Vec<u8>, params: KmeansParams) -> Vec<CentroidData<Lab>> {
+    // Convert RGB [u8] buffer to Lab for k-means
+    let lab: Vec<Lab> = from_component_slice::<Srgb<u8>>(&img_vec)
+        .iter()
+        .map(|x| x.into_format().into_color())
+        .collect();
+
+    // Iterate over the runs, keep the best results
+    let mut result = Kmeans::new();
+    for i in 0..KMEANS_RUNS {
+        let run_result = get_kmeans(
+            params.clusters,
+            params.max_iter,
+            params.converge,
+            false,
+            &lab,
+            30 + i,
+        );
+        if run_result.score < result.score {
+            result = run_result;
+        }
+    }
+
+    // Using the results, process the centroid data
+    Lab::sort_indexed_colors(&result.centroids, &result.indices)
+}
+
+/// Histogram matching of the cells' dominant colors against `palette`: every tile cell is moved to a palette color,
+/// and each palette color is given to (about) its share of the cells. Cells closest to a palette color with room left
+/// are matched first. Cells that aren't tile cells keep their color.
+fn match_histogram(
+    cell_colors: &[Lab],
+    palette: &[CentroidData<Lab>],
+    is_tile_cell: impl Fn(usize) -> bool,
+) -> Vec<Lab> {
+    let tile_cells: Vec<usize> = (0..cell_colors.len())
+        .filter(|&idx| is_tile_cell(idx))
+        .collect();
+    let mut capacity: Vec<usize> = palette
+        .iter()
+        .map(|entry| (entry.percentage * tile_cells.len() as f32).ceil() as usize)
+        .collect();
+
+    // every (cell, palette entry) pair, closest first
+    let mut pairs: Vec<(f32, usize, usize)> = tile_cells
+        .iter()
+        .flat_map(|&idx| {
+            palette.iter().enumerate().map(move |(entry, data)| {
+                (cell_colors[idx].distance_squared(data.centroid), idx, entry)
+            })
+        })
+        .collect();
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut matched = cell_colors.to_vec();
+    let mut done = vec![false; cell_colors.len()];
+    for (_, idx, entry) in pairs {
+        if done[idx] || capacity[entry] == 0 {
+            continue;
+        }
+        matched[idx] = palette[entry].centroid;
+        capacity[entry] -= 1;
+        done[idx] = true;
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, RgbaImage};
+
+    #[test]
+    fn complementary_of_grey_stays_neutral() {
+        for l in [0.0, 25.0, 50.0, 100.0] {
+            let complement = complementary_lab(Lab::new(l, 0.0, 0.0));
+            assert!(complement.a.abs() < 1e-4 && complement.b.abs() < 1e-4);
+            assert!((complement.l - (100.0 - l)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn median_of_black_and_white_is_grey() {
+        let mut img_vec = vec![0u8; 3 * 8];
+        img_vec.extend([255u8; 3 * 8]);
+        let median = median_color(img_vec);
+        assert!((median.l - 50.0).abs() < 0.1);
+        assert!(median.a.abs() < 0.1 && median.b.abs() < 0.1);
+    }
+
+    fn solid_png(width: u32, height: u32, color: [u8; 3]) -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb(color)));
+        let mut png = Vec::new();
+        OutputFormat::Png.write(&img, &mut png, None).unwrap();
+        png
+    }
+
+    #[test]
+    fn min_unique_tiles_counts_mirrored_tile_cells() {
+        let pool: Vec<Vec<u8>> = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]]
+            .into_iter()
+            .map(|color| solid_png(8, 8, color))
+            .collect();
+        let reference = DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 40, Rgb([128; 3])));
+
+        let mut args = Args::library_defaults("", "");
+        args.cols = 4;
+        args.rows = 4;
+        args.mirror = Some(Mirror::Quad);
+        args.strategy = Strategy::BestMatch;
+        args.min_unique_tiles = 4;
+
+        let recreate = Recreate::new();
+        recreate.read_memory_to_vec(&pool, &args).unwrap();
+        // best match puts one image everywhere, the shuffle fallback has to spread all four over the 4 tile cells
+        assert!(recreate.collage_to_png(reference.clone(), &args).is_ok());
+
+        args.min_unique_tiles = 5;
+        let err = recreate.collage_to_png(reference, &args).unwrap_err();
+        assert!(err.to_string().contains("4 tile cells"));
+    }
+
+    #[cfg(feature = "hdr")]
+    #[test]
+    fn reload_pool_tone_maps_with_the_given_mapper() {
+        struct Black;
+        impl ToneMapper for Black {
+            fn map(&self, _value: f32) -> f32 {
+                0.0
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("recreate-tonemap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bright = image::Rgb32FImage::from_pixel(4, 4, Rgb([2.0; 3]));
+        DynamicImage::ImageRgb32F(bright)
+            .save(dir.join("bright.hdr"))
+            .unwrap();
+
+        let recreate = Recreate::new();
+        let loaded = recreate.reload_pool_with_tone_mapper(dir.to_str().unwrap(), &Black);
+        fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+        assert_eq!(
+            recreate.pool().img_list[0].to_rgb8().get_pixel(0, 0),
+            &Rgb([0; 3])
+        );
+    }
+
+    #[test]
+    fn scale_cell_opacity_keeps_the_colors() {
+        let mut buffer = RgbaImage::from_pixel(4, 4, Rgba([200, 100, 50, 200]));
+        scale_cell_opacity(&mut buffer, (0, 0), 2, 2, 1.0 - FREQ_OPACITY_STEP);
+        assert_eq!(buffer.get_pixel(1, 1), &Rgba([200, 100, 50, 180]));
+        assert_eq!(buffer.get_pixel(2, 2), &Rgba([200, 100, 50, 200]));
+    }
+
+    #[test]
+    fn next_divisor_of_a_prime_is_the_prime() {
+        assert!(is_prime(7919) && !is_prime(7917));
+        assert_eq!(next_divisor(13, 2).unwrap(), 13);
+        assert_eq!(next_divisor(7919, 5).unwrap(), 7919);
+    }
+
+    #[test]
+    fn next_divisor_near_u32_max() {
+        // the largest u32 prime, and u32::MAX = 3 * 5 * 17 * 257 * 65537
+        assert!(is_prime(4_294_967_291) && !is_prime(u32::MAX));
+        assert_eq!(next_divisor(4_294_967_291, 2).unwrap(), 4_294_967_291);
+        assert_eq!(next_divisor(u32::MAX, 65_538).unwrap(), 3 * 65_537);
+        assert_eq!(next_divisor(u32::MAX, 196_612).unwrap(), 5 * 65_537);
+    }
+
+    #[test]
+    fn next_divisor_starting_at_n() {
+        assert_eq!(next_divisor(12, 12).unwrap(), 12);
+        assert_eq!(next_divisor(u32::MAX, u32::MAX).unwrap(), u32::MAX);
+        assert!(next_divisor(12, 13).is_err());
+    }
+
+    #[test]
+    fn auto_grid_follows_the_reference_aspect() {
+        // 50 cells, 10x5 is exact, matches 2:1 and divides the image
+        assert_eq!(auto_grid(50, 1000, 500, 1), (10, 5));
+        assert_eq!(auto_grid(25, 500, 1000, 2), (5, 10));
+        // no factor pair of 30 divides 770, so the closest to square wins
+        assert_eq!(auto_grid(30, 770, 770, 1), (5, 6));
+    }
+
+    #[test]
+    fn auto_grid_falls_back_when_no_factor_pair_fits() {
+        // 7 cells only split into 1x7 and 7x1, neither fits a 5x5 image
+        assert_eq!(auto_grid(7, 5, 5, 1), (3, 3));
+    }
+
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, ((x + y) * 8) as u8, 255])
+        })
+    }
+
+    #[test]
+    fn quantize_palette_has_at_most_the_requested_colors() {
+        for colors in [2, 5, 16] {
+            let mut buf = gradient(16, 16);
+            let palette = postprocess::quantize(&mut buf, colors, false);
+            assert!(!palette.is_empty() && palette.len() <= colors as usize);
+            assert!(buf.pixels().all(|pixel| palette.contains(pixel)));
+        }
+        // never more entries than distinct colors
+        let mut buf = gradient(2, 2);
+        assert_eq!(postprocess::quantize(&mut buf, 256, false).len(), 4);
+    }
+
+    #[test]
+    fn dithered_pixels_are_palette_colors() {
+        let mut buf = gradient(16, 16);
+        let palette = postprocess::quantize(&mut buf, 4, true);
+        assert!(palette.len() <= 4);
+        assert!(buf.pixels().all(|pixel| palette.contains(pixel)));
+    }
+}
@@ -0,0 +1,79 @@
+//! Pool images listed in a JSON manifest, as local paths or HTTP(S) URLs.
+
+use crate::download::{self, HttpClient};
+use anyhow::{Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Reads the manifest at `path`, a JSON array of image paths (relative to the manifest) and HTTP(S) URLs,
+/// and returns a local path for every entry. URLs are downloaded into `cache_dir` unless they already are,
+/// the ones that can't be downloaded are reported and skipped. Their partial downloads are removed unless
+/// `keep_temp` is set.
+pub fn resolve_manifest(
+    path: &str,
+    cache_dir: &Path,
+    user_agent: Option<&str>,
+    keep_temp: bool,
+) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read manifest in specified path: {}", path))?;
+    let entries: Vec<String> = serde_json::from_str(&text).with_context(|| {
+        format!(
+            "Manifest {} should be a JSON array of image paths and URLs",
+            path
+        )
+    })?;
+
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    let client = HttpClient::new(user_agent);
+    let mut paths = Vec::new();
+    for entry in entries {
+        if !download::is_url(&entry) {
+            paths.push(base.join(entry));
+            continue;
+        }
+        match fetch(&client, &entry, cache_dir, keep_temp) {
+            Ok(path) => paths.push(path),
+            Err(e) => eprintln!("Skipping {}: {:#}", entry, e),
+        }
+    }
+    Ok(paths)
+}
+
+/// File name a downloaded `url` is cached as, its own file name prefixed with a hash of the whole URL so
+/// different images with the same name don't clash.
+fn cache_name(url: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    format!("{:016x}-{}", hasher.finish(), download::file_name(url))
+}
+
+/// Returns the cached copy of `url` in `cache_dir`, downloading it first if there is none.
+fn fetch(client: &HttpClient, url: &str, cache_dir: &Path, keep_temp: bool) -> Result<PathBuf> {
+    let cached = cache_dir.join(cache_name(url));
+    if cached.exists() {
+        return Ok(cached);
+    }
+
+    fs::create_dir_all(cache_dir).with_context(|| {
+        format!(
+            "Couldn't create manifest cache directory {}",
+            cache_dir.display()
+        )
+    })?;
+    // downloaded under a temporary name first so an interrupted download isn't mistaken for a cached image
+    let mut partial_name = cached.file_name().unwrap_or_default().to_owned();
+    partial_name.push(".part");
+    let partial = cached.with_file_name(partial_name);
+    if let Err(e) = client.download(url, &partial) {
+        if keep_temp && partial.exists() {
+            return Err(e.context(format!("partial download kept at {}", partial.display())));
+        }
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, &cached)?;
+    Ok(cached)
+}
@@ -0,0 +1,120 @@
+//! Image embeddings from an ONNX classification model (MobileNetV2, EfficientNet-Lite, ...) for --distance-metric nn.
+//! The ONNX runtime is loaded at runtime, from ORT_DYLIB_PATH or the library search path.
+
+use anyhow::{anyhow, Context, Result};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use ort::session::Session;
+use ort::value::Tensor;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::Mutex;
+
+#[cfg(not(feature = "parallel"))]
+use crate::sequential::ParallelSlice;
+
+/// Side length of the square images the model takes
+const INPUT_SIZE: u32 = 224;
+/// Number of images run through the model at once
+const BATCH_SIZE: usize = 32;
+/// ImageNet channel means and standard deviations the models are trained with
+const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const STD: [f32; 3] = [0.229, 0.224, 0.225];
+/// ONNX runtime library loaded when ORT_DYLIB_PATH isn't set
+#[cfg(target_os = "windows")]
+const DEFAULT_DYLIB: &str = "onnxruntime.dll";
+#[cfg(target_os = "macos")]
+const DEFAULT_DYLIB: &str = "libonnxruntime.dylib";
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const DEFAULT_DYLIB: &str = "libonnxruntime.so";
+
+/// An ONNX model taking a `[N, 3, 224, 224]` batch of normalized RGB images, whose first output is used as the embedding.
+pub struct FeatureExtractor {
+    // running a session needs exclusive access
+    session: Mutex<Session>,
+}
+
+impl fmt::Debug for FeatureExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FeatureExtractor").finish_non_exhaustive()
+    }
+}
+
+impl FeatureExtractor {
+    pub fn new(model_path: &str) -> Result<Self> {
+        // loading the runtime up front turns a missing library into an error rather than a panic in ort
+        let dylib = std::env::var("ORT_DYLIB_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| DEFAULT_DYLIB.to_owned());
+        ort::init_from(&dylib).with_context(|| {
+            format!(
+                "Couldn't load the ONNX runtime library {}, set ORT_DYLIB_PATH to its location",
+                dylib
+            )
+        })?;
+        let session = Session::builder()
+            .and_then(|mut builder| builder.commit_from_file(model_path))
+            .with_context(|| format!("Couldn't load ONNX model: {}", model_path))?;
+        Ok(Self {
+            session: Mutex::new(session),
+        })
+    }
+
+    /// L2 normalized embedding of every image, in the same order.
+    pub fn embed(&self, images: &[DynamicImage]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(images.len());
+        for batch in images.chunks(BATCH_SIZE) {
+            // the runtime parallelizes the model itself, only preprocessing is spread over threads here
+            let input = batch
+                .par_iter()
+                .map(preprocess)
+                .collect::<Vec<_>>()
+                .concat();
+            let size = INPUT_SIZE as usize;
+            let tensor = Tensor::from_array(([batch.len(), 3, size, size], input))?;
+            let mut session = self.session.lock().unwrap();
+            let outputs = session.run(ort::inputs![tensor])?;
+            let (_, output) = outputs[0].try_extract_tensor::<f32>()?;
+            if output.is_empty() || output.len() % batch.len() != 0 {
+                return Err(anyhow!(
+                    "Unexpected ONNX model output of {} values for a batch of {}",
+                    output.len(),
+                    batch.len()
+                ));
+            }
+            embeddings.extend(output.chunks(output.len() / batch.len()).map(normalize));
+        }
+        Ok(embeddings)
+    }
+}
+
+/// Resizes the image to the model input and lays it out as normalized planar (CHW) RGB.
+fn preprocess(img: &DynamicImage) -> Vec<f32> {
+    let rgb = img
+        .resize_exact(INPUT_SIZE, INPUT_SIZE, FilterType::Triangle)
+        .to_rgb8();
+    let plane = (INPUT_SIZE * INPUT_SIZE) as usize;
+    let mut input = vec![0.0; 3 * plane];
+    for (idx, pixel) in rgb.pixels().enumerate() {
+        for channel in 0..3 {
+            input[channel * plane + idx] =
+                (pixel[channel] as f32 / 255.0 - MEAN[channel]) / STD[channel];
+        }
+    }
+    input
+}
+
+fn normalize(embedding: &[f32]) -> Vec<f32> {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return embedding.to_vec();
+    }
+    embedding.iter().map(|x| x / norm).collect()
+}
+
+/// Cosine distance between two normalized embeddings, 0 for identical directions and 2 for opposite ones.
+pub fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>()
+}
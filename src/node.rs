@@ -0,0 +1,143 @@
+//! Node.js addon around [`Recreate`], enabled with the `napi` feature and built with the napi-rs CLI,
+//! which also writes the TypeScript declarations. See package.json.
+
+use crate::{open_ref, Args, Recreate, Strategy};
+use clap::ValueEnum;
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::{Env, Error, Result, Task};
+use napi_derive::napi;
+use std::sync::Arc;
+
+fn napi_err(e: anyhow::Error) -> Error {
+    Error::from_reason(format!("{:#}", e))
+}
+
+/// Options of a collage, named like the command line arguments. Unset options keep their defaults.
+/// `collage()` throws right away for options outside the ranges given here, rather than rejecting its promise.
+#[napi(object)]
+pub struct CollageOptions {
+    /// Path to the image to recreate
+    #[napi(js_name = "ref")]
+    pub reference: String,
+    /// Number of grid columns, at least 1, adjusted up to a divisor of the image width
+    pub cols: Option<u32>,
+    /// Number of grid rows, at least 1, adjusted up to a divisor of the image height
+    pub rows: Option<u32>,
+    /// How much tiles are blended towards their cell's color, from 0.0 to 1.0
+    pub alpha: Option<f64>,
+    /// How much each pixel of the tiles is saturated, from 0.0 to 0.1
+    pub saturation: Option<f64>,
+    /// How tiles are picked for the cells: random, best-match, shuffle, anneal or luminance-match
+    pub strategy: Option<String>,
+    /// Maximum number of times a pool image may be used
+    pub max_reuse: Option<u32>,
+}
+
+impl CollageOptions {
+    fn to_args(&self) -> anyhow::Result<Args> {
+        if self.cols == Some(0) || self.rows == Some(0) {
+            return Err(anyhow::anyhow!("cols and rows should be at least 1"));
+        }
+        if self
+            .alpha
+            .is_some_and(|alpha| !(0.0..=1.0).contains(&alpha))
+        {
+            return Err(anyhow::anyhow!("alpha should be between 0.0 and 1.0"));
+        }
+        if self
+            .saturation
+            .is_some_and(|saturation| !(0.0..=0.1).contains(&saturation))
+        {
+            return Err(anyhow::anyhow!("saturation should be between 0.0 and 0.1"));
+        }
+        let mut args = Args::library_defaults("", &self.reference);
+        args.cols = self.cols.unwrap_or(args.cols);
+        args.rows = self.rows.unwrap_or(args.rows);
+        args.alpha = self.alpha.map_or(args.alpha, |alpha| alpha as f32);
+        args.saturation = self
+            .saturation
+            .map_or(args.saturation, |saturation| saturation as f32);
+        if let Some(strategy) = &self.strategy {
+            args.strategy = Strategy::from_str(strategy, true).map_err(anyhow::Error::msg)?;
+        }
+        args.max_reuse = self.max_reuse.unwrap_or(args.max_reuse);
+        Ok(args)
+    }
+}
+
+pub struct LoadDir {
+    recreate: Arc<Recreate>,
+    dir: String,
+}
+
+impl Task for LoadDir {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        let args = Args::library_defaults(&self.dir, "");
+        self.recreate
+            .read_dir_to_vec(&self.dir, "", &args)
+            .map_err(napi_err)
+    }
+
+    fn resolve(&mut self, _env: Env, output: ()) -> Result<()> {
+        Ok(output)
+    }
+}
+
+pub struct BuildCollage {
+    recreate: Arc<Recreate>,
+    args: Args,
+}
+
+impl Task for BuildCollage {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Vec<u8>> {
+        let img = open_ref(&self.args).map_err(napi_err)?;
+        self.recreate
+            .collage_to_png(img, &self.args)
+            .map_err(napi_err)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<u8>) -> Result<Buffer> {
+        Ok(output.into())
+    }
+}
+
+/// Pool of tile images that collages are built from.
+/// The work runs on the libuv thread pool, collages of the same pool are built concurrently while loading
+/// images waits for them.
+#[napi(js_name = "Recreate")]
+#[derive(Default)]
+pub struct JsRecreate {
+    recreate: Arc<Recreate>,
+}
+
+#[napi]
+impl JsRecreate {
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds every image in the directory `dir` to the pool.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn load_dir(&self, dir: String) -> AsyncTask<LoadDir> {
+        AsyncTask::new(LoadDir {
+            recreate: Arc::clone(&self.recreate),
+            dir,
+        })
+    }
+
+    /// Builds a collage of `opts.ref` out of the loaded pool, resolving to the PNG encoded collage.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn collage(&self, opts: CollageOptions) -> Result<AsyncTask<BuildCollage>> {
+        Ok(AsyncTask::new(BuildCollage {
+            recreate: Arc::clone(&self.recreate),
+            args: opts.to_args().map_err(napi_err)?,
+        }))
+    }
+}
@@ -0,0 +1,231 @@
+//! Colour grading and quantization applied to the finished collage before it is saved, quantization also to every tile
+//! with --cell-quantize.
+
+use crate::lab_to_rgba_u8;
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageBuffer, Rgba};
+use palette::{IntoColor, Lab, Lch, Srgb};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::Write;
+
+#[cfg(not(feature = "parallel"))]
+use crate::sequential::{ParallelSlice, ParallelSliceMut};
+
+/// Rotates the hue of every pixel by `degrees` around the LCh color wheel (--hue-rotate).
+/// Lightness, chroma and alpha are kept, colors rotated out of the sRGB gamut are clamped.
+pub fn apply_hue_rotate(buf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, degrees: f32) {
+    buf.par_chunks_mut(4).for_each(|pixel| {
+        let rgb: Srgb<f32> = Srgb::new(pixel[0], pixel[1], pixel[2]).into_format();
+        let mut lch: Lch = rgb.into_color();
+        lch.hue += degrees;
+        let lab: Lab = lch.into_color();
+        pixel.copy_from_slice(&lab_to_rgba_u8(lab, Some(pixel[3])).0);
+    });
+}
+
+/// Reduces `buf` to at most `colors` colors (--palette-quantize, --cell-quantize) and returns the palette, which every pixel of `buf`
+/// is an entry of afterwards.
+/// The palette comes from a median cut of the RGBA color space, weighted by pixel count, and every pixel is mapped
+/// to its nearest palette entry, or error diffused over its neighbours with `dither` (--dither).
+pub fn quantize(
+    buf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    colors: u32,
+    dither: bool,
+) -> Vec<Rgba<u8>> {
+    let mut counts: HashMap<[u8; 4], u64> = HashMap::new();
+    for pixel in buf.pixels() {
+        *counts.entry(pixel.0).or_default() += 1;
+    }
+    let distinct: Vec<[u8; 4]> = counts.keys().copied().collect();
+
+    let mut boxes = vec![counts.into_iter().collect::<Vec<_>>()];
+    while boxes.len() < colors as usize {
+        // split the box spanning the widest channel range at its median along that channel
+        let Some((box_idx, channel, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(idx, colors)| {
+                let (channel, range) = widest_channel(colors);
+                (idx, channel, range)
+            })
+            .max_by_key(|&(_, _, range)| range)
+        else {
+            break;
+        };
+        let mut lower = boxes.swap_remove(box_idx);
+        lower.sort_unstable_by_key(|(color, _)| color[channel]);
+        let half = lower.iter().map(|(_, count)| count).sum::<u64>() / 2;
+        let mut seen = 0;
+        let median = lower
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen > half
+            })
+            .unwrap_or(0);
+        let upper = lower.split_off(median.clamp(1, lower.len() - 1));
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    let palette: Vec<Rgba<u8>> = boxes.iter().map(|colors| mean_color(colors)).collect();
+    if dither {
+        floyd_steinberg_dither(buf, &palette);
+        return palette;
+    }
+    let nearest: HashMap<[u8; 4], Rgba<u8>> = distinct
+        .par_iter()
+        .map(|&color| (color, nearest_entry(&palette, color)))
+        .collect();
+    for pixel in buf.pixels_mut() {
+        *pixel = nearest[&pixel.0];
+    }
+    palette
+}
+
+/// The channel whose values differ the most in `colors`, with that difference.
+fn widest_channel(colors: &[([u8; 4], u64)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = colors.iter().map(|(color, _)| color[channel]);
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+/// Mean of `colors` weighted by their pixel counts.
+fn mean_color(colors: &[([u8; 4], u64)]) -> Rgba<u8> {
+    let total = colors.iter().map(|(_, count)| count).sum::<u64>().max(1);
+    let mut mean = [0; 4];
+    for (channel, value) in mean.iter_mut().enumerate() {
+        let sum: u64 = colors
+            .iter()
+            .map(|(color, count)| color[channel] as u64 * count)
+            .sum();
+        *value = ((sum + total / 2) / total) as u8;
+    }
+    Rgba(mean)
+}
+
+fn nearest_entry(palette: &[Rgba<u8>], color: [u8; 4]) -> Rgba<u8> {
+    let distance = |entry: &Rgba<u8>| {
+        entry
+            .0
+            .iter()
+            .zip(color)
+            .map(|(&a, b)| (a as i32 - b as i32).pow(2))
+            .sum::<i32>()
+    };
+    *palette.iter().min_by_key(|entry| distance(entry)).unwrap()
+}
+
+/// Maps every pixel of `buf` to its nearest `palette` entry with Floyd-Steinberg error diffusion: the difference
+/// between a pixel and its entry is passed on to the pixels not yet mapped, 7/16 to the right, 3/16 below left,
+/// 5/16 below and 1/16 below right, so areas between two entries mix them instead of banding.
+/// Rows are scanned alternately left to right and right to left (serpentine), which avoids diagonal artifacts.
+pub fn floyd_steinberg_dither(buf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, palette: &[Rgba<u8>]) {
+    let (width, height) = (buf.width() as usize, buf.height() as usize);
+    if width == 0 || palette.is_empty() {
+        return;
+    }
+    // error carried to the current and to the next row, per pixel and channel; one pixel of padding on each side
+    // so the diffusion needs no bounds checks
+    let mut current = vec![[0.0f32; 4]; width + 2];
+    let mut next = vec![[0.0f32; 4]; width + 2];
+    let mut nearest: HashMap<[u8; 4], Rgba<u8>> = HashMap::new();
+
+    for y in 0..height {
+        let left_to_right = y % 2 == 0;
+        for step in 0..width {
+            let x = if left_to_right {
+                step
+            } else {
+                width - 1 - step
+            };
+            let pixel = buf.get_pixel_mut(x as u32, y as u32);
+
+            let mut wanted = [0.0f32; 4];
+            let mut color = [0u8; 4];
+            for channel in 0..4 {
+                wanted[channel] = pixel[channel] as f32 + current[x + 1][channel];
+                color[channel] = wanted[channel].round().clamp(0.0, 255.0) as u8;
+            }
+            let entry = *nearest
+                .entry(color)
+                .or_insert_with(|| nearest_entry(palette, color));
+            *pixel = entry;
+
+            // the neighbours ahead are on the right when scanning right, on the left otherwise
+            let (ahead, behind) = if left_to_right {
+                (x + 2, x)
+            } else {
+                (x, x + 2)
+            };
+            for channel in 0..4 {
+                let error = wanted[channel] - entry[channel] as f32;
+                current[ahead][channel] += error * 7.0 / 16.0;
+                next[behind][channel] += error * 3.0 / 16.0;
+                next[x + 1][channel] += error * 5.0 / 16.0;
+                next[ahead][channel] += error * 1.0 / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill([0.0; 4]);
+    }
+}
+
+/// Encodes `img`, whose pixels are all entries of `palette` (see `quantize`), as a palette-indexed PNG.
+pub fn write_indexed_png(
+    img: &DynamicImage,
+    palette: &[Rgba<u8>],
+    writer: impl Write,
+    icc_profile: Option<&[u8]>,
+) -> Result<()> {
+    let index: HashMap<[u8; 4], u8> = palette
+        .iter()
+        .enumerate()
+        .map(|(idx, entry)| (entry.0, idx as u8))
+        .collect();
+    let rgba = img.to_rgba8();
+    let data: Vec<u8> = rgba
+        .pixels()
+        .map(|pixel| {
+            index
+                .get(&pixel.0)
+                .copied()
+                .ok_or_else(|| anyhow!("pixel {:?} isn't in the palette", pixel.0))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut info = png::Info::with_size(rgba.width(), rgba.height());
+    info.color_type = png::ColorType::Indexed;
+    info.bit_depth = png::BitDepth::Eight;
+    info.palette = Some(
+        palette
+            .iter()
+            .flat_map(|entry| [entry[0], entry[1], entry[2]])
+            .collect::<Vec<u8>>()
+            .into(),
+    );
+    // the alpha of every entry, left out when they are all opaque
+    if palette.iter().any(|entry| entry[3] != u8::MAX) {
+        info.trns = Some(
+            palette
+                .iter()
+                .map(|entry| entry[3])
+                .collect::<Vec<u8>>()
+                .into(),
+        );
+    }
+    info.icc_profile = icc_profile.map(Into::into);
+
+    let mut writer = png::Encoder::with_info(writer, info)?.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
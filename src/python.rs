@@ -0,0 +1,80 @@
+//! Python module around [`Recreate`], enabled with the `python` feature and built with maturin.
+
+use crate::{open_ref, Args, Recreate};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+fn py_err(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", e))
+}
+
+/// Pool of tile images that collages are built from.
+///
+/// Load pool images with ``load_dir``, then build any number of collages with ``collage``.
+#[pyclass(name = "Recreate", module = "recreate")]
+struct PyRecreate(Recreate);
+
+#[pymethods]
+impl PyRecreate {
+    #[new]
+    fn new() -> Self {
+        PyRecreate(Recreate::new())
+    }
+
+    /// Adds every image in a directory to the pool.
+    ///
+    /// :param path: directory containing the pool images.
+    /// :raises RuntimeError: if the directory can't be read or holds no images.
+    fn load_dir(&self, py: Python<'_>, path: &str) -> PyResult<()> {
+        let args = Args::library_defaults(path, "");
+        py.detach(|| self.0.read_dir_to_vec(path, "", &args))
+            .map_err(py_err)
+    }
+
+    /// Builds a collage of a reference image out of the loaded pool.
+    ///
+    /// :param ref_path: path to the image to recreate.
+    /// :param cols: number of grid columns, adjusted up to a divisor of the image width.
+    /// :param rows: number of grid rows, adjusted up to a divisor of the image height.
+    /// :param alpha: how much tiles are blended towards their cell's color, from 0.0 to 1.0.
+    /// :param in_memory: return the collage as PNG bytes instead of saving it as ``output.png``
+    ///     in the reference image's directory.
+    /// :returns: the PNG bytes if ``in_memory`` is set, otherwise ``None``.
+    /// :raises RuntimeError: if the reference image can't be read or the collage can't be built or saved.
+    #[pyo3(signature = (ref_path, cols = 70, rows = 70, alpha = 0.7, in_memory = false))]
+    fn collage<'py>(
+        &self,
+        py: Python<'py>,
+        ref_path: &str,
+        cols: u32,
+        rows: u32,
+        alpha: f32,
+        in_memory: bool,
+    ) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let mut args = Args::library_defaults("", ref_path);
+        args.cols = cols;
+        args.rows = rows;
+        args.alpha = alpha;
+
+        let recreate = &self.0;
+        let png = py
+            .detach(|| {
+                if in_memory {
+                    open_ref(&args)
+                        .and_then(|img| recreate.collage_to_png(img, &args))
+                        .map(Some)
+                } else {
+                    recreate.collage(&args).map(|_| None)
+                }
+            })
+            .map_err(py_err)?;
+        Ok(png.map(|png| PyBytes::new(py, &png)))
+    }
+}
+
+/// Photomosaics built out of a pool of images.
+#[pymodule]
+fn recreate(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyRecreate>()
+}
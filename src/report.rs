@@ -0,0 +1,153 @@
+use crate::{rgba_to_hex, CollageStats, KMEANS_RUNS};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+// Maximum number of swatches shown in the palette section
+const MAX_SWATCHES: usize = 32;
+
+/// Writes the statistics of a finished collage run to `path` as a JSON object.
+pub fn write_stats_json(stats: &CollageStats, elapsed: Duration, path: &str) -> Result<()> {
+    let json = serde_json::json!({
+        "ref_path": stats.ref_path,
+        "output_path": stats.output_path,
+        "width": stats.width,
+        "height": stats.height,
+        "grid_cols": stats.grid_cols,
+        "grid_rows": stats.grid_rows,
+        "pool_size": stats.pool_size,
+        "strategy": stats.strategy.to_string(),
+        "alpha": stats.alpha,
+        "quality_score": stats.quality_score,
+        "elapsed_seconds": elapsed.as_secs_f64(),
+    });
+    fs::write(path, serde_json::to_string_pretty(&json)?)
+        .with_context(|| format!("Couldn't write run statistics in path: {}", path))
+}
+
+/// Writes a self-contained HTML report (no external dependencies) for a finished collage run.
+pub struct HtmlReportWriter {
+    stats: CollageStats,
+    elapsed: Duration,
+}
+
+impl HtmlReportWriter {
+    pub fn new(stats: CollageStats, elapsed: Duration) -> Self {
+        Self { stats, elapsed }
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let html = self.render()?;
+        fs::write(path, html)
+            .with_context(|| format!("Couldn't write HTML report in path: {}", path))
+    }
+
+    fn render(&self) -> Result<String> {
+        let stats = &self.stats;
+        let collage_bytes = fs::read(&stats.output_path).with_context(|| {
+            format!(
+                "Couldn't read collage for HTML report in path: {}",
+                stats.output_path
+            )
+        })?;
+
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str("<title>Recreate report</title>\n<style>\n");
+        html.push_str("body { font-family: sans-serif; margin: 2em; }\n");
+        html.push_str("table { border-collapse: collapse; margin-bottom: 2em; }\n");
+        html.push_str("td, th { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }\n");
+        html.push_str("table.grid td { border: none; padding: 0; width: 6px; height: 6px; }\n");
+        html.push_str("img { max-width: 100%; }\n");
+        html.push_str("</style>\n</head>\n<body>\n<h1>Recreate report</h1>\n");
+
+        // collage
+        let mime = match stats.output_path.rsplit('.').next() {
+            Some("jpg") => "image/jpeg",
+            Some("webp") => "image/webp",
+            _ => "image/png",
+        };
+        writeln!(
+            html,
+            "<h2>Collage</h2>\n<img src=\"data:{};base64,{}\" alt=\"collage\">",
+            mime,
+            STANDARD.encode(collage_bytes)
+        )?;
+
+        // run statistics
+        html.push_str("<h2>Run statistics</h2>\n<table>\n");
+        let rows = [
+            ("Reference image", escape(&stats.ref_path)),
+            ("Output image", escape(&stats.output_path)),
+            ("Output size", format!("{}x{}", stats.width, stats.height)),
+            (
+                "Grid",
+                format!("{} cols x {} rows", stats.grid_cols, stats.grid_rows),
+            ),
+            ("Pool size", stats.pool_size.to_string()),
+            ("Strategy", stats.strategy.to_string()),
+            ("Alpha", stats.alpha.to_string()),
+            ("k-means clusters", stats.kmeans.clusters.to_string()),
+            ("k-means max iterations", stats.kmeans.max_iter.to_string()),
+            ("k-means convergence", stats.kmeans.converge.to_string()),
+            ("k-means runs", KMEANS_RUNS.to_string()),
+            (
+                "Quality score",
+                stats
+                    .quality_score
+                    .map_or_else(|| "-".to_owned(), |score| format!("{:.1}", score)),
+            ),
+            ("Time taken", format!("{:?}", self.elapsed)),
+        ];
+        for (name, value) in rows {
+            writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value)?;
+        }
+        html.push_str("</table>\n");
+
+        // most common dominant colors across all cells
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for &color in &stats.cell_colors {
+            *counts.entry(rgba_to_hex(color)).or_default() += 1;
+        }
+        let mut palette: Vec<(String, usize)> = counts.into_iter().collect();
+        palette.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        html.push_str("<h2>Dominant color palette</h2>\n<table>\n");
+        html.push_str("<tr><th>Swatch</th><th>Color</th><th>Cells</th></tr>\n");
+        for (hex, count) in palette.iter().take(MAX_SWATCHES) {
+            writeln!(
+                html,
+                "<tr><td style=\"background:{0}; width:40px\"></td><td>{0}</td><td>{1}</td></tr>",
+                hex, count
+            )?;
+        }
+        html.push_str("</table>\n");
+
+        // one table cell per grid cell, colored by its dominant color
+        html.push_str("<h2>Grid</h2>\n<table class=\"grid\">\n");
+        for row in stats.cell_colors.chunks(stats.grid_cols as usize) {
+            html.push_str("<tr>");
+            for &color in row {
+                write!(
+                    html,
+                    "<td style=\"background:{}\"></td>",
+                    rgba_to_hex(color)
+                )?;
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+
+        Ok(html)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
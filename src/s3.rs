@@ -0,0 +1,108 @@
+//! Pool images stored in S3, enabled with the `s3` feature.
+
+use anyhow::{anyhow, Context, Result};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::Client;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+// Objects downloaded at the same time
+const MAX_CONCURRENT_DOWNLOADS: usize = 16;
+
+/// Downloads the objects under `prefix` in `bucket` into `cache_dir`, skipping the ones already there with the same size.
+/// Keys are flattened into file names below the prefix, a/b.jpg becomes a_b.jpg.
+/// Credentials come from the standard AWS environment variables and files, as does the region unless one is given.
+/// Returns the number of objects downloaded.
+pub fn sync_prefix(
+    bucket: &str,
+    prefix: &str,
+    region: Option<&str>,
+    cache_dir: &Path,
+) -> Result<usize> {
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("Couldn't create S3 cache directory {}", cache_dir.display()))?;
+    tokio::runtime::Runtime::new()?.block_on(sync(bucket, prefix, region, cache_dir))
+}
+
+async fn sync(bucket: &str, prefix: &str, region: Option<&str>, cache_dir: &Path) -> Result<usize> {
+    let mut config = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(region) = region {
+        config = config.region(Region::new(region.to_owned()));
+    }
+    let client = Client::new(&config.load().await);
+
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_DOWNLOADS));
+    let mut downloads = JoinSet::new();
+    while let Some(page) = pages.next().await {
+        let page = page.with_context(|| format!("Couldn't list s3://{}/{}", bucket, prefix))?;
+        for object in page.contents() {
+            let Some(key) = object.key() else {
+                continue;
+            };
+            let name = key[prefix.len()..]
+                .trim_start_matches('/')
+                .replace('/', "_");
+            // "folders" created through the console are empty objects ending in /
+            if name.is_empty() || key.ends_with('/') {
+                continue;
+            }
+
+            let dest = cache_dir.join(name);
+            let size = object.size().unwrap_or_default();
+            let cached = tokio::fs::metadata(&dest)
+                .await
+                .is_ok_and(|meta| meta.len() as i64 == size);
+            if cached {
+                continue;
+            }
+
+            let (client, bucket, key) = (client.clone(), bucket.to_owned(), key.to_owned());
+            let permit = Arc::clone(&permits).acquire_owned().await?;
+            downloads.spawn(async move {
+                let result = download(&client, &bucket, &key, &dest).await;
+                drop(permit);
+                result.with_context(|| format!("Couldn't download s3://{}/{}", bucket, key))
+            });
+        }
+    }
+
+    let mut downloaded = 0;
+    while let Some(result) = downloads.join_next().await {
+        match result.map_err(|e| anyhow!(e)).and_then(|result| result) {
+            Ok(()) => downloaded += 1,
+            Err(e) => eprintln!("Skipping {:#}", e),
+        }
+    }
+    Ok(downloaded)
+}
+
+async fn download(client: &Client, bucket: &str, key: &str, dest: &PathBuf) -> Result<()> {
+    let mut body = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await?
+        .body;
+
+    // written under a temporary name first so an interrupted download isn't mistaken for a cached image
+    let mut partial_name = dest.file_name().unwrap_or_default().to_owned();
+    partial_name.push(".part");
+    let partial = dest.with_file_name(partial_name);
+    let mut file = tokio::fs::File::create(&partial).await?;
+    while let Some(bytes) = body.try_next().await? {
+        file.write_all(&bytes).await?;
+    }
+    file.flush().await?;
+    tokio::fs::rename(&partial, dest).await?;
+    Ok(())
+}
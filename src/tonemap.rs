@@ -0,0 +1,77 @@
+use image::{DynamicImage, ImageBuffer, Rgba};
+
+/// Maps a linear HDR channel value (0 to infinity) to the linear 0-1 display range.
+pub trait ToneMapper: Sync {
+    fn map(&self, value: f32) -> f32;
+}
+
+/// Reinhard's global operator, x / (1 + x).
+pub struct Reinhard;
+
+impl ToneMapper for Reinhard {
+    fn map(&self, value: f32) -> f32 {
+        value / (1.0 + value)
+    }
+}
+
+/// Krzysztof Narkowicz's fit of the ACES filmic curve.
+pub struct Aces;
+
+impl ToneMapper for Aces {
+    fn map(&self, value: f32) -> f32 {
+        let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+        (value * (a * value + b)) / (value * (c * value + d) + e)
+    }
+}
+
+/// John Hable's Uncharted 2 filmic curve, normalised so a linear value of 11.2 maps to white.
+pub struct Filmic;
+
+impl Filmic {
+    const WHITE_POINT: f32 = 11.2;
+
+    fn curve(x: f32) -> f32 {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    }
+}
+
+impl ToneMapper for Filmic {
+    fn map(&self, value: f32) -> f32 {
+        // the usual exposure bias of 2 for this curve
+        Self::curve(value * 2.0) / Self::curve(Self::WHITE_POINT)
+    }
+}
+
+/// Whether `img` holds floating point (HDR) pixels, as decoded from .hdr and .exr files.
+pub fn is_hdr(img: &DynamicImage) -> bool {
+    matches!(
+        img,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    )
+}
+
+/// Tone maps an HDR image down to 8 bit sRGB with `mapper`, alpha is kept as is.
+pub fn tonemap(img: &DynamicImage, mapper: &dyn ToneMapper) -> DynamicImage {
+    let hdr = img.to_rgba32f();
+    let encode = |value: f32| {
+        let linear = mapper.map(value.max(0.0)).clamp(0.0, 1.0);
+        // sRGB transfer function
+        let encoded = if linear <= 0.003_130_8 {
+            linear * 12.92
+        } else {
+            1.055 * linear.powf(1.0 / 2.4) - 0.055
+        };
+        (encoded * 255.0).round() as u8
+    };
+
+    DynamicImage::ImageRgba8(ImageBuffer::from_fn(hdr.width(), hdr.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *hdr.get_pixel(x, y);
+        Rgba([
+            encode(r),
+            encode(g),
+            encode(b),
+            (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+        ])
+    }))
+}
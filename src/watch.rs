@@ -0,0 +1,177 @@
+use crate::{build_and_report, download, output_dir, read_pool_filter_csv, status, Args, Recreate};
+use anyhow::{Context, Result};
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// Files a collage run writes next to the reference image besides output.<format>, changes to these are ignored
+const DEBUG_OUTPUTS: [&str; 4] = [
+    "ref_grid.png",
+    "debug_grid.png",
+    "debug_indices.png",
+    "distances_heatmap.png",
+];
+
+/// The paths a watch is interested in.
+struct Watched<'a> {
+    pool_dir: PathBuf,
+    // Directory of the reference image, which the outputs are written to
+    ref_dir: PathBuf,
+    ref_name: &'a str,
+    // A downloaded reference image can't be watched, only the pool directory is then
+    ref_url: bool,
+    // File names of the outputs in ref_dir
+    outputs: Vec<String>,
+    // The --output file, when it is one
+    output: Option<PathBuf>,
+}
+
+/// What a batch of filesystem events changed.
+#[derive(Default)]
+struct Changes {
+    ref_changed: bool,
+    // New files in the pool directory
+    added: BTreeSet<PathBuf>,
+}
+
+/// Watches the pool directory and the reference image, adding new pool images to `recreate` and rebuilding the
+/// collage after every batch of changes. Runs until the process is interrupted.
+/// `ref_name` is the reference image's file name, which is never added to the pool.
+pub fn watch(recreate: &Recreate, args: &Args, ref_name: &str) -> Result<()> {
+    let mut outputs: Vec<String> = args
+        .output_formats
+        .iter()
+        .map(|format| format!("output.{}", format.extension()))
+        .collect();
+    outputs.extend(DEBUG_OUTPUTS.map(str::to_owned));
+    let output = match args.output.as_deref() {
+        Some(path) if path != "-" => {
+            let path = Path::new(path);
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            Some(
+                canonical_dir(dir.unwrap_or(Path::new(".")))?
+                    .join(path.file_name().unwrap_or_default()),
+            )
+        }
+        _ => None,
+    };
+    let watched = Watched {
+        pool_dir: canonical_dir(Path::new(&args.dir))?,
+        ref_dir: canonical_dir(output_dir(&args.r#ref))?,
+        ref_name,
+        ref_url: download::is_url(&args.r#ref),
+        outputs,
+        output,
+    };
+    let included = args
+        .pool_filter_csv
+        .as_deref()
+        .map(read_pool_filter_csv)
+        .transpose()?;
+
+    // the watcher delivers events from its own background thread
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&watched.pool_dir, RecursiveMode::NonRecursive)?;
+    if watched.ref_url {
+        status!("watching {} for changes...", args.dir);
+    } else {
+        if watched.ref_dir != watched.pool_dir {
+            watcher.watch(&watched.ref_dir, RecursiveMode::NonRecursive)?;
+        }
+        status!("watching {} and {} for changes...", args.dir, args.r#ref);
+    }
+
+    let debounce = Duration::from_millis(args.watch_debounce);
+    while let Ok(event) = receiver.recv() {
+        let mut events = vec![event];
+        // wait for the changes to settle, copying a batch of photos fires plenty of events
+        while let Ok(event) = receiver.recv_timeout(debounce) {
+            events.push(event);
+        }
+
+        let mut changes = Changes::default();
+        for event in events {
+            match event {
+                Ok(event) => changes.record(event, &watched),
+                Err(e) => eprintln!("Watch error: {:?}", e),
+            }
+        }
+
+        let mut added = 0;
+        for path in &changes.added {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if included
+                .as_ref()
+                .is_some_and(|included| !name.is_some_and(|name| included.contains(name)))
+            {
+                continue;
+            }
+            // anything that isn't an image (yet) is skipped rather than ending the watch
+            match recreate.add_pool_image(path, args) {
+                Ok(()) => added += 1,
+                Err(e) => eprintln!("Skipping {}: {:#}", path.display(), e),
+            }
+        }
+        if added > 0 {
+            status!("added {} images to the pool", added);
+        }
+
+        if changes.ref_changed || added > 0 {
+            if let Err(e) = build_and_report(recreate, args, Instant::now()) {
+                eprintln!("Error: {:#}", e);
+            }
+            status!("watching for changes...");
+        }
+    }
+
+    Ok(())
+}
+
+impl Changes {
+    fn record(&mut self, event: Event, watched: &Watched) {
+        let created = matches!(
+            event.kind,
+            EventKind::Create(CreateKind::File | CreateKind::Any)
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both))
+        );
+        let modified = created || matches!(event.kind, EventKind::Modify(ModifyKind::Data(_)));
+        if !modified {
+            return;
+        }
+
+        // renames report the old and the new path, only the last one is still there
+        let paths = match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => &event.paths[1..],
+            _ => &event.paths[..],
+        };
+        for path in paths {
+            let (Some(name), Some(dir)) = (
+                path.file_name().and_then(|name| name.to_str()),
+                path.parent().and_then(|dir| fs::canonicalize(dir).ok()),
+            ) else {
+                continue;
+            };
+
+            if dir == watched.ref_dir && watched.outputs.iter().any(|output| output == name) {
+                continue;
+            }
+            if watched.output.as_deref() == Some(&dir.join(name)) {
+                continue;
+            }
+            if name == watched.ref_name {
+                self.ref_changed |= !watched.ref_url && dir == watched.ref_dir;
+            } else if created && dir == watched.pool_dir {
+                self.added.insert(path.clone());
+            }
+        }
+    }
+}
+
+fn canonical_dir(dir: &Path) -> Result<PathBuf> {
+    fs::canonicalize(dir).with_context(|| format!("Couldn't watch directory {}", dir.display()))
+}